@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS facilities (
+    id TEXT NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+-- existing measurements were all taken from the main gym
+INSERT INTO facilities (id, name) VALUES ('gym', 'Gym') ON CONFLICT DO NOTHING;
+
+ALTER TABLE measurements ADD COLUMN facility_id TEXT NOT NULL DEFAULT 'gym' REFERENCES facilities (id);
+ALTER TABLE measurements ALTER COLUMN facility_id DROP DEFAULT;
+
+ALTER TABLE measurements DROP CONSTRAINT measurements_pkey;
+ALTER TABLE measurements ADD PRIMARY KEY (facility_id, measured_at);
@@ -0,0 +1,12 @@
+-- Pre-aggregated daily statistics, maintained from measurements by the rollup task
+CREATE TABLE IF NOT EXISTS daily_rollups (
+    facility_id TEXT NOT NULL REFERENCES facilities (id),
+    day DATE NOT NULL,
+    avg REAL NOT NULL,
+    min SMALLINT NOT NULL,
+    max SMALLINT NOT NULL,
+    -- UTC hour of the day with the highest average
+    peak_hour SMALLINT NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (facility_id, day)
+);
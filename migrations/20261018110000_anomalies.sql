@@ -0,0 +1,16 @@
+-- Unexpected readings, each row covering a run of consecutive readings with the same kind of anomaly
+CREATE TABLE IF NOT EXISTS anomalies (
+    id BIGSERIAL PRIMARY KEY,
+    facility_id TEXT NOT NULL REFERENCES facilities (id),
+    -- 'spike', 'stuck' or 'zero'
+    kind TEXT NOT NULL,
+    started_at TIMESTAMPTZ NOT NULL,
+    -- measured_at of the most recent reading in the run
+    ended_at TIMESTAMPTZ NOT NULL,
+    -- reading that started the run
+    value SMALLINT NOT NULL,
+    -- typical value of the slot the run started in
+    expected REAL
+);
+
+CREATE INDEX IF NOT EXISTS anomalies_facility_id_ended_at ON anomalies (facility_id, ended_at);
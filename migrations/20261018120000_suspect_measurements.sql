@@ -0,0 +1,2 @@
+-- Measurements believed not to reflect the actual occupancy, such as those repeated by a frozen upstream page
+ALTER TABLE measurements ADD COLUMN IF NOT EXISTS suspect BOOLEAN NOT NULL DEFAULT FALSE;
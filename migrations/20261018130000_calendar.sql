@@ -0,0 +1,22 @@
+-- Tagged periods such as terms, vacations and exams, imported from iCalendar files
+CREATE TABLE IF NOT EXISTS calendar_periods (
+    uid TEXT PRIMARY KEY,
+    kind TEXT NOT NULL,
+    summary TEXT NOT NULL,
+    starts DATE NOT NULL,
+    -- exclusive
+    ends DATE NOT NULL,
+    CHECK (starts < ends)
+);
+
+CREATE INDEX IF NOT EXISTS calendar_periods_starts_ends ON calendar_periods (starts, ends);
+
+-- Kind of the period containing a day, preferring the most recently started and then the shortest when periods overlap, or NULL if there is none
+CREATE OR REPLACE FUNCTION calendar_kind(day DATE) RETURNS TEXT
+LANGUAGE SQL STABLE AS $$
+    SELECT kind
+    FROM calendar_periods
+    WHERE starts <= day AND day < ends
+    ORDER BY starts DESC, ends ASC
+    LIMIT 1
+$$;
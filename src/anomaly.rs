@@ -0,0 +1,316 @@
+//! Detection of unexpected readings
+//!
+//! Each new reading is compared against the typical value of its slot on the same day of the week. Readings far from the typical value are flagged as spikes, which usually mean the facility is unusually busy or quiet, while identical readings for a long time and readings of 0% during opening hours are flagged as stuck and zero, which usually mean the scraper or upstream page is broken.
+//!
+//! Stuck readings are also marked as suspect, excluding them from typical values and forecasts.
+
+use {
+    crate::{
+        config::Config,
+        error::Error,
+        hours::OpeningHours,
+        routes::history::{Aggregate, HistoryQuery, DEFAULT_TRIM, DEFAULT_WEEKS, WEEK},
+        status::Reading,
+    },
+    chrono::{DateTime, DurationRound, TimeDelta, Utc},
+    sqlx::{Pool, Postgres},
+    std::{fmt, time::Duration},
+    tracing::{error, warn},
+};
+
+/// Size of the slots whose typical value readings are compared against
+const INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Number of days added to the lookback of stuck runs to cover closures within them
+const LOOKBACK_CLOSED_DAYS: i64 = 3;
+
+/// Kind of unexpected reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// Far above or below the typical value
+    Spike,
+    /// Identical to every reading for a long time during opening hours
+    Stuck,
+    /// 0% during opening hours
+    Zero,
+}
+
+impl AnomalyKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Spike => "spike",
+            Self::Stuck => "stuck",
+            Self::Zero => "zero",
+        }
+    }
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Thresholds for flagging readings as anomalies
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyDetector {
+    /// Percentage points by which a reading must differ from the typical value to be a spike
+    pub spike_threshold: f64,
+    /// Length of opening hours after which identical readings are stuck
+    pub stuck_after: TimeDelta,
+}
+
+impl AnomalyDetector {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            spike_threshold: f64::from(config.anomaly_spike_threshold),
+            // validated by `Config::new`, but a config value should never crash the fetcher
+            stuck_after: i64::try_from(config.anomaly_stuck_after)
+                .ok()
+                .and_then(TimeDelta::try_minutes)
+                .unwrap_or(TimeDelta::MAX),
+        }
+    }
+
+    /// Checks a stored reading, recording and logging any anomalies
+    ///
+    /// A reading with the same kind of anomaly as the previous reading extends the existing anomaly rather than creating a new one.
+    pub async fn check(
+        &self,
+        db: &Pool<Postgres>,
+        facility_id: &str,
+        hours: &OpeningHours,
+        reading: &Reading,
+    ) -> Result<(), Error> {
+        let expected = typical(db, facility_id, reading.measured_at).await?;
+        let open = hours.is_open(reading.measured_at);
+
+        let deviates = expected.is_some_and(|expected| {
+            (f64::from(reading.value) - expected).abs() > self.spike_threshold
+        });
+
+        let mut anomalies = vec![];
+
+        if open && reading.value == 0 {
+            anomalies.push((AnomalyKind::Zero, reading.measured_at));
+        } else if deviates {
+            anomalies.push((AnomalyKind::Spike, reading.measured_at));
+        }
+
+        if open {
+            if let Some(started_at) = self.stuck_since(db, facility_id, hours, reading).await? {
+                mark_suspect(db, facility_id, started_at, reading.measured_at).await?;
+                anomalies.push((AnomalyKind::Stuck, started_at));
+            }
+        }
+
+        for (kind, started_at) in anomalies {
+            record(db, facility_id, kind, started_at, reading, expected).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Gets the start of the run of identical readings ending with `reading`, if it is long enough to be stuck
+    async fn stuck_since(
+        &self,
+        db: &Pool<Postgres>,
+        facility_id: &str,
+        hours: &OpeningHours,
+        reading: &Reading,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        // only looks back twice the threshold, plus a few days for closures within the run, so that long runs do not scan every measurement
+        let lookback = self
+            .stuck_after
+            .checked_mul(2)
+            .and_then(|lookback| lookback.checked_add(&TimeDelta::days(LOOKBACK_CLOSED_DAYS)))
+            .and_then(|lookback| reading.measured_at.checked_sub_signed(lookback))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+        let started_at = sqlx::query_scalar!(
+            r#"
+                SELECT MIN(measured_at)
+                FROM measurements
+                WHERE
+                    facility_id = $1 AND
+                    measured_at <= $2 AND
+                    measured_at >= $4 AND
+                    measured_at > COALESCE(
+                        (
+                            SELECT MAX(measured_at)
+                            FROM measurements
+                            WHERE facility_id = $1 AND measured_at <= $2 AND measured_at >= $4 AND value <> $3
+                        ),
+                        '-infinity'
+                    )
+            "#,
+            facility_id,
+            reading.measured_at,
+            i16::from(reading.value),
+            lookback,
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(started_at.filter(|started_at| self.is_stuck(hours, *started_at, reading.measured_at)))
+    }
+
+    /// Whether a run of identical readings from `started_at` to `at` lasted long enough to be stuck, counting only opening hours
+    fn is_stuck(&self, hours: &OpeningHours, started_at: DateTime<Utc>, at: DateTime<Utc>) -> bool {
+        hours.open_time(started_at, at) >= self.stuck_after
+    }
+}
+
+/// Gets the typical value of the slot containing `at` on the same day of the week and calendar period kind, ignoring measurements of 0% and suspect measurements
+async fn typical(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+    at: DateTime<Utc>,
+) -> Result<Option<f64>, Error> {
+    let interval = TimeDelta::from_std(INTERVAL).unwrap();
+    let slot = at.duration_trunc(interval).unwrap() - TimeDelta::from_std(WEEK).unwrap();
+
+    let history = HistoryQuery {
+        from: slot,
+        to: slot,
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        periods: DEFAULT_WEEKS,
+        period: WEEK,
+        trim: DEFAULT_TRIM,
+        exclude_zero: true,
+        exclude_suspect: true,
+        same_period_as: Some(at.date_naive()),
+    }
+    .fetch(db, facility_id)
+    .await?;
+
+    Ok(history.values.first().copied().flatten())
+}
+
+/// Marks the measurements of a facility from `from` to `to` inclusive as suspect
+async fn mark_suspect(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE measurements SET suspect = TRUE WHERE facility_id = $1 AND measured_at >= $2 AND measured_at <= $3 AND NOT suspect",
+        facility_id,
+        from,
+        to,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Extends the anomaly of the previous reading if it is of the same kind, otherwise records and logs a new one
+async fn record(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+    kind: AnomalyKind,
+    started_at: DateTime<Utc>,
+    reading: &Reading,
+    expected: Option<f64>,
+) -> Result<(), sqlx::Error> {
+    let extended = sqlx::query_scalar!(
+        r#"
+            UPDATE anomalies
+            SET ended_at = $3
+            WHERE
+                facility_id = $1 AND
+                kind = $2 AND
+                ended_at = (
+                    SELECT MAX(measured_at)
+                    FROM measurements
+                    WHERE facility_id = $1 AND measured_at < $3
+                )
+            RETURNING id
+        "#,
+        facility_id,
+        kind.as_str(),
+        reading.measured_at,
+    )
+    .fetch_optional(db)
+    .await?
+    .is_some();
+
+    if extended {
+        return Ok(());
+    }
+
+    sqlx::query!(
+        "INSERT INTO anomalies (facility_id, kind, started_at, ended_at, value, expected) VALUES ($1, $2, $3, $4, $5, $6)",
+        facility_id,
+        kind.as_str(),
+        started_at,
+        reading.measured_at,
+        i16::from(reading.value),
+        expected.map(|expected| expected as f32),
+    )
+    .execute(db)
+    .await?;
+
+    let expected = expected.map_or("unknown".to_owned(), |expected| format!("{expected:.0}%"));
+    match kind {
+        // likely real, so not reported as an error
+        AnomalyKind::Spike => warn!(
+            "Unusual reading for {facility_id}: {}%, typically {expected}",
+            reading.value
+        ),
+        AnomalyKind::Stuck | AnomalyKind::Zero => error!(
+            "Possibly broken readings for {facility_id}: {kind} anomaly at {}%, typically {expected}, since {started_at}",
+            reading.value
+        ),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, chrono::NaiveDate};
+
+    fn at(day: u32, h: u32, m: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    fn detector(stuck_after: i64) -> AnomalyDetector {
+        AnomalyDetector {
+            spike_threshold: 30.0,
+            stuck_after: TimeDelta::minutes(stuck_after),
+        }
+    }
+
+    #[test]
+    fn run_across_closure_is_not_stuck() {
+        // open 6:00 to 22:00, so the run lasts 10 minutes of opening hours over 8 hours and 10 minutes
+        let hours = OpeningHours::default();
+
+        assert!(!detector(300).is_stuck(&hours, at(12, 21, 55), at(13, 6, 5)));
+    }
+
+    #[test]
+    fn run_during_opening_hours_is_stuck() {
+        let hours = OpeningHours::default();
+        let detector = detector(120);
+
+        assert!(!detector.is_stuck(&hours, at(12, 12, 0), at(12, 13, 59)));
+        assert!(detector.is_stuck(&hours, at(12, 12, 0), at(12, 14, 0)));
+    }
+
+    #[test]
+    fn run_counts_opening_hours_on_both_sides_of_closure() {
+        // an hour before closing and an hour after opening
+        let hours = OpeningHours::default();
+
+        assert!(detector(120).is_stuck(&hours, at(12, 21, 0), at(13, 7, 0)));
+    }
+}
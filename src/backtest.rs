@@ -0,0 +1,273 @@
+//! Offline evaluation of occupancy predictions against stored measurements
+//!
+//! Predictions are made day by day at regular origins using only the measurements before each origin, then compared with the average measured occupancy of each predicted slot.
+
+use {
+    crate::{
+        error::Error,
+        forecast::{self, Forecast, INTERVAL},
+        hours::WEEKDAYS,
+        routes::history::{Aggregate, HistoryQuery, DAY, DEFAULT_TRIM},
+    },
+    chrono::{DateTime, Datelike, DurationRound, NaiveDate, TimeDelta, Utc, Weekday},
+    clap::ValueEnum,
+    serde::Serialize,
+    sqlx::{Pool, Postgres},
+    std::{fmt, time::Duration},
+};
+
+/// Number of past days averaged by the baseline predictor
+const BASELINE_DAYS: u32 = 7;
+
+/// Method of predicting occupancy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Predictor {
+    /// Trimmed average of each slot over the previous 7 days, ignoring measurements of 0% and suspect measurements
+    Baseline,
+    /// Short-term forecast, see `crate::forecast`
+    Forecast,
+}
+
+impl Predictor {
+    /// Predicts the occupancy of each slot for `horizon` after `origin`, using only measurements made before `origin`
+    pub async fn predict(
+        self,
+        db: &Pool<Postgres>,
+        facility: &str,
+        origin: DateTime<Utc>,
+        horizon: Duration,
+    ) -> Result<Forecast, Error> {
+        match self {
+            Self::Baseline => baseline(db, facility, origin, horizon).await,
+            Self::Forecast => forecast::forecast(db, facility, origin, horizon).await,
+        }
+    }
+}
+
+/// Error of predictions for a single horizon
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct HorizonError {
+    /// Minutes between the start of the slot containing the origin and the start of the predicted slot
+    pub horizon: u64,
+    /// Number of predictions with an actual value to compare against
+    pub count: usize,
+    /// Mean absolute error in percentage points
+    pub mae: f64,
+    /// Root mean squared error in percentage points
+    pub rmse: f64,
+}
+
+/// Errors of predictions made on a single day of the week
+#[derive(Debug, Clone, Serialize)]
+pub struct WeekdayErrors {
+    pub weekday: Weekday,
+    pub horizons: Vec<HorizonError>,
+}
+
+/// Result of a backtest
+#[derive(Debug, Clone, Serialize)]
+pub struct BacktestReport {
+    pub facility: String,
+    pub predictor: Predictor,
+    /// First replayed day
+    pub from: NaiveDate,
+    /// Last replayed day, inclusive
+    pub to: NaiveDate,
+    /// Number of predictions made
+    pub predictions: usize,
+    /// Errors over all days
+    pub horizons: Vec<HorizonError>,
+    /// Errors by day of the week of the origin
+    pub weekdays: Vec<WeekdayErrors>,
+}
+
+/// Replays predictions made every `step` on each day from `from` to `to` against the stored measurements of a facility
+pub async fn backtest(
+    db: &Pool<Postgres>,
+    facility: &str,
+    predictor: Predictor,
+    from: NaiveDate,
+    to: NaiveDate,
+    step: Duration,
+    horizon: Duration,
+) -> Result<BacktestReport, Error> {
+    let step = TimeDelta::from_std(step).unwrap();
+    let slots = horizon.as_secs().div_ceil(INTERVAL.as_secs()) as usize;
+
+    // signed errors by weekday and slot
+    let mut errors = vec![vec![Vec::<f64>::new(); slots]; WEEKDAYS.len()];
+    let mut predictions = 0;
+
+    for day in from.iter_days().take_while(|day| *day <= to) {
+        let start = day.and_time(Default::default()).and_utc();
+        let weekday = &mut errors[day.weekday().num_days_from_monday() as usize];
+
+        let mut origin = start;
+        while origin < start + TimeDelta::days(1) {
+            let prediction = predictor.predict(db, facility, origin, horizon).await?;
+            let actual = actual(db, facility, &prediction).await?;
+            predictions += 1;
+
+            for (slot, (predicted, actual)) in prediction.values.iter().zip(actual).enumerate() {
+                if let (Some(predicted), Some(actual)) = (predicted, actual) {
+                    weekday[slot].push(predicted - actual);
+                }
+            }
+
+            origin += step;
+        }
+    }
+
+    let horizons = (0..slots)
+        .map(|slot| {
+            let all = errors
+                .iter()
+                .flat_map(|weekday| &weekday[slot])
+                .copied()
+                .collect::<Vec<_>>();
+            summarise(slot, &all)
+        })
+        .collect();
+
+    let weekdays = WEEKDAYS
+        .iter()
+        .zip(&errors)
+        .map(|(weekday, errors)| WeekdayErrors {
+            weekday: *weekday,
+            horizons: errors
+                .iter()
+                .enumerate()
+                .map(|(slot, errors)| summarise(slot, errors))
+                .collect(),
+        })
+        .collect();
+
+    Ok(BacktestReport {
+        facility: facility.to_owned(),
+        predictor,
+        from,
+        to,
+        predictions,
+        horizons,
+        weekdays,
+    })
+}
+
+/// Trimmed average of each slot over the previous `BASELINE_DAYS` days, like the typical day of the average route
+pub async fn baseline(
+    db: &Pool<Postgres>,
+    facility: &str,
+    origin: DateTime<Utc>,
+    horizon: Duration,
+) -> Result<Forecast, Error> {
+    let interval = TimeDelta::from_std(INTERVAL).unwrap();
+    let day = TimeDelta::from_std(DAY).unwrap();
+    let first = origin.duration_trunc(interval).unwrap() + interval;
+    let slots = horizon.as_secs().div_ceil(INTERVAL.as_secs()) as i32;
+
+    let history = HistoryQuery {
+        from: first - day,
+        to: first - day + interval * (slots - 1),
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        periods: BASELINE_DAYS,
+        period: DAY,
+        trim: DEFAULT_TRIM,
+        exclude_zero: true,
+        exclude_suspect: true,
+        same_period_as: None,
+    }
+    .fetch(db, facility)
+    .await?;
+
+    Ok(Forecast {
+        first,
+        interval: INTERVAL,
+        values: history.values.into_iter().rev().collect(),
+    })
+}
+
+/// Gets the average measured occupancy of each slot of a prediction, oldest first
+pub async fn actual(
+    db: &Pool<Postgres>,
+    facility: &str,
+    prediction: &Forecast,
+) -> Result<Vec<Option<f64>>, Error> {
+    let interval = TimeDelta::from_std(prediction.interval).unwrap();
+    let slots = prediction.values.len().saturating_sub(1) as i32;
+
+    let history = HistoryQuery {
+        from: prediction.first,
+        to: prediction.first + interval * slots,
+        interval: prediction.interval,
+        aggregate: Aggregate::Avg,
+        periods: 1,
+        period: DAY,
+        trim: 0.0,
+        exclude_zero: false,
+        exclude_suspect: true,
+        same_period_as: None,
+    }
+    .fetch(db, facility)
+    .await?;
+
+    Ok(history.values.into_iter().rev().collect())
+}
+
+/// Computes error statistics of a slot from its signed errors
+fn summarise(slot: usize, errors: &[f64]) -> HorizonError {
+    let horizon = (slot as u64 + 1) * INTERVAL.as_secs() / 60;
+
+    if errors.is_empty() {
+        return HorizonError {
+            horizon,
+            ..Default::default()
+        };
+    }
+
+    let count = errors.len() as f64;
+
+    HorizonError {
+        horizon,
+        count: errors.len(),
+        mae: errors.iter().map(|e| e.abs()).sum::<f64>() / count,
+        rmse: (errors.iter().map(|e| e * e).sum::<f64>() / count).sqrt(),
+    }
+}
+
+impl fmt::Display for BacktestReport {
+    /// Plain text tables of the errors over all days and by day of the week
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:?} predictions for {} from {} to {} ({} predictions)",
+            self.predictor, self.facility, self.from, self.to, self.predictions
+        )?;
+
+        let tables = [("All days".to_owned(), &self.horizons)].into_iter().chain(
+            self.weekdays
+                .iter()
+                .map(|weekday| (weekday.weekday.to_string(), &weekday.horizons)),
+        );
+
+        for (title, horizons) in tables {
+            writeln!(f)?;
+            writeln!(f, "{title}")?;
+            writeln!(
+                f,
+                "{:>8} {:>7} {:>7} {:>7}",
+                "horizon", "count", "mae", "rmse"
+            )?;
+            for h in horizons {
+                writeln!(
+                    f,
+                    "{:>6}m {:>7} {:>7.2} {:>7.2}",
+                    h.horizon, h.count, h.mae, h.rmse
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
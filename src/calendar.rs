@@ -0,0 +1,300 @@
+//! Calendar of tagged periods, such as terms, vacations and exams
+//!
+//! Periods are imported from the events of iCalendar files, tagged either with an explicit kind or the first category of each event. Typical values are only combined over days of the same kind, see the `calendar_kind` SQL function.
+
+use {
+    chrono::{NaiveDate, TimeDelta},
+    sqlx::{Pool, Postgres},
+};
+
+/// Single tagged period of whole days
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalendarPeriod {
+    /// Unique identifier of the event, used to replace the period when reimported
+    pub uid: String,
+    pub kind: String,
+    pub summary: String,
+    /// First day of the period
+    pub starts: NaiveDate,
+    /// Day after the last day of the period
+    pub ends: NaiveDate,
+}
+
+/// Error occurred while parsing an iCalendar file
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum CalendarError {
+    /// Event {0:?} has no DTSTART
+    MissingStart(String),
+    /// Event {0:?} has no kind, specify one or add a CATEGORIES property
+    MissingKind(String),
+    /// Invalid date {0:?}
+    InvalidDate(String),
+    /// Event {0:?} ends before it starts
+    InvalidRange(String),
+}
+
+/// Parses the events of an iCalendar file into periods, tagged with `kind` or otherwise the first category of each event
+pub fn parse_ics(text: &str, kind: Option<&str>) -> Result<Vec<CalendarPeriod>, CalendarError> {
+    let mut periods = vec![];
+    let mut event: Option<Vec<(String, String)>> = None;
+
+    for line in unfold(text) {
+        let Some((name, value)) = property(&line) else {
+            continue;
+        };
+
+        match (name.as_str(), value.as_str(), &mut event) {
+            ("BEGIN", "VEVENT", _) => event = Some(vec![]),
+            ("END", "VEVENT", Some(properties)) => {
+                periods.push(period(properties, kind)?);
+                event = None;
+            }
+            (_, _, Some(properties)) => properties.push((name, value)),
+            _ => (),
+        }
+    }
+
+    Ok(periods)
+}
+
+/// Inserts periods, replacing any previously imported with the same UIDs, returning the number of periods
+pub async fn import(db: &Pool<Postgres>, periods: &[CalendarPeriod]) -> Result<u64, sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    for period in periods {
+        sqlx::query!(
+            r#"
+                INSERT INTO calendar_periods (uid, kind, summary, starts, ends)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (uid) DO UPDATE SET
+                    kind = EXCLUDED.kind,
+                    summary = EXCLUDED.summary,
+                    starts = EXCLUDED.starts,
+                    ends = EXCLUDED.ends
+            "#,
+            period.uid,
+            period.kind,
+            period.summary,
+            period.starts,
+            period.ends,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(periods.len() as u64)
+}
+
+/// Joins lines continued with leading whitespace
+fn unfold(text: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+
+    for line in text.lines() {
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continuation), Some(last)) => last.push_str(continuation),
+            _ => lines.push(line.to_owned()),
+        }
+    }
+
+    lines
+}
+
+/// Splits a content line into its upper case name, without parameters, and its value
+fn property(line: &str) -> Option<(String, String)> {
+    let (name, value) = line.split_once(':')?;
+    let name = name.split(';').next()?.trim().to_ascii_uppercase();
+
+    Some((name, value.trim().to_owned()))
+}
+
+fn period(
+    properties: &[(String, String)],
+    kind: Option<&str>,
+) -> Result<CalendarPeriod, CalendarError> {
+    let get = |name: &str| {
+        properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let summary = get("SUMMARY").map(unescape).unwrap_or_default();
+
+    let starts = get("DTSTART")
+        .ok_or_else(|| CalendarError::MissingStart(summary.clone()))
+        .and_then(|value| date(value, false))?;
+    let ends = get("DTEND")
+        .map(|value| date(value, true))
+        .transpose()?
+        .unwrap_or(starts + TimeDelta::days(1));
+
+    if ends <= starts {
+        return Err(CalendarError::InvalidRange(summary));
+    }
+
+    let kind = kind
+        .map(str::to_owned)
+        .or_else(|| {
+            get("CATEGORIES")
+                .and_then(|categories| categories.split(',').next())
+                .map(unescape)
+        })
+        .map(|kind| kind.trim().to_lowercase())
+        .filter(|kind| !kind.is_empty())
+        .ok_or_else(|| CalendarError::MissingKind(summary.clone()))?;
+
+    Ok(CalendarPeriod {
+        uid: get("UID").map_or_else(|| format!("{starts}/{summary}"), unescape),
+        kind,
+        summary,
+        starts,
+        ends,
+    })
+}
+
+/// Parses the date of a `DATE` or `DATE-TIME` value, rounding end times after midnight up to the next day so that the period includes their day
+fn date(value: &str, end: bool) -> Result<NaiveDate, CalendarError> {
+    let invalid = || CalendarError::InvalidDate(value.to_owned());
+
+    let date = NaiveDate::parse_from_str(value.get(..8).ok_or_else(invalid)?, "%Y%m%d")
+        .map_err(|_| invalid())?;
+
+    let after_midnight = value
+        .get(9..15)
+        .is_some_and(|time| time.bytes().any(|b| b != b'0'));
+
+    Ok(if end && after_midnight {
+        date + TimeDelta::days(1)
+    } else {
+        date
+    })
+}
+
+/// Removes the escaping of a text value
+fn unescape(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n' | 'N') => text.push('\n'),
+            Some(c) => text.push(c),
+            None => (),
+        }
+    }
+
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn event(properties: &str) -> String {
+        format!("BEGIN:VCALENDAR\r\nBEGIN:VEVENT\r\n{properties}END:VEVENT\r\nEND:VCALENDAR\r\n")
+    }
+
+    #[test]
+    fn parses_all_day_event() {
+        let text = event(
+            "UID:term-1\r\nSUMMARY:Martinmas\r\nCATEGORIES:Term,Teaching\r\nDTSTART;VALUE=DATE:20260914\r\nDTEND;VALUE=DATE:20261219\r\n",
+        );
+
+        assert_eq!(
+            parse_ics(&text, None).unwrap(),
+            vec![CalendarPeriod {
+                uid: "term-1".to_owned(),
+                kind: "term".to_owned(),
+                summary: "Martinmas".to_owned(),
+                starts: day(2026, 9, 14),
+                ends: day(2026, 12, 19),
+            }]
+        );
+    }
+
+    #[test]
+    fn unfolds_continued_lines() {
+        let text = event(
+            "UID:break\r\nSUMMARY:Independent \r\n learning\r\n\t week\r\nDTSTART:20261026\r\n",
+        );
+        let periods = parse_ics(&text, Some("Vacation")).unwrap();
+
+        assert_eq!(periods[0].summary, "Independent learning week");
+        assert_eq!(periods[0].kind, "vacation");
+    }
+
+    #[test]
+    fn unescapes_text() {
+        let text = event(
+            "SUMMARY:Exams\\, resits\\; marking\\nand \\\\ more\r\nCATEGORIES:exam\r\nDTSTART:20261201\r\n",
+        );
+        let periods = parse_ics(&text, None).unwrap();
+
+        assert_eq!(periods[0].summary, "Exams, resits; marking\nand \\ more");
+        // without a UID the period is identified by its start and summary
+        assert_eq!(
+            periods[0].uid,
+            "2026-12-01/Exams, resits; marking\nand \\ more"
+        );
+    }
+
+    #[test]
+    fn date_time_ends_include_their_day() {
+        let text =
+            event("SUMMARY:Open day\r\nDTSTART:20261024T090000Z\r\nDTEND:20261024T170000Z\r\n");
+        let periods = parse_ics(&text, Some("event")).unwrap();
+
+        assert_eq!(periods[0].starts, day(2026, 10, 24));
+        assert_eq!(periods[0].ends, day(2026, 10, 25));
+    }
+
+    #[test]
+    fn date_time_ends_at_midnight_exclude_their_day() {
+        let text = event("SUMMARY:Week\r\nDTSTART:20261019T000000\r\nDTEND:20261026T000000\r\n");
+        let periods = parse_ics(&text, Some("event")).unwrap();
+
+        assert_eq!(periods[0].ends, day(2026, 10, 26));
+    }
+
+    #[test]
+    fn missing_end_lasts_one_day() {
+        let text = event("SUMMARY:Holiday\r\nDTSTART;VALUE=DATE:20261130\r\n");
+        let periods = parse_ics(&text, Some("vacation")).unwrap();
+
+        assert_eq!(periods[0].ends, day(2026, 12, 1));
+    }
+
+    #[test]
+    fn rejects_invalid_events() {
+        assert!(matches!(
+            parse_ics(&event("SUMMARY:No start\r\n"), Some("term")),
+            Err(CalendarError::MissingStart(_))
+        ));
+        assert!(matches!(
+            parse_ics(&event("SUMMARY:No kind\r\nDTSTART:20261019\r\n"), None),
+            Err(CalendarError::MissingKind(_))
+        ));
+        assert!(matches!(
+            parse_ics(&event("DTSTART:2026-10-19\r\n"), Some("term")),
+            Err(CalendarError::InvalidDate(_))
+        ));
+        assert!(matches!(
+            parse_ics(
+                &event("DTSTART:20261019\r\nDTEND:20261018\r\n"),
+                Some("term")
+            ),
+            Err(CalendarError::InvalidRange(_))
+        ));
+    }
+}
@@ -0,0 +1,491 @@
+//! Command line interface
+
+use {
+    crate::{
+        anomaly::AnomalyDetector,
+        backtest::{backtest, Predictor},
+        calendar::{self, parse_ics},
+        export::{self, ExportFormat},
+        import::{self, ImportFormat, OnConflict},
+        leader::LeaderLock,
+        migrate, rollup,
+        source::HtmlSource,
+        start,
+        status::{store_reading, Reading},
+        Config,
+    },
+    chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc},
+    clap::{Args, Parser, Subcommand, ValueEnum},
+    color_eyre::eyre::{bail, ensure, Result, WrapErr},
+    regex::Regex,
+    sqlx::{
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Pool, Postgres,
+    },
+    std::{
+        fs::{self, File},
+        io::{self, BufWriter, Write},
+        path::PathBuf,
+        time::Duration,
+    },
+    tokio_stream::StreamExt,
+};
+
+/// Largest allowed backtest horizon in hours, predictions must not reach the slots averaged by the baseline
+const MAX_BACKTEST_HOURS: u64 = 12;
+
+/// Default number of days replayed by a backtest
+const DEFAULT_BACKTEST_DAYS: i64 = 28;
+
+/// Maximum number of invalid rows printed before the rest are only counted
+const MAX_PRINTED_ROW_ERRORS: usize = 20;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the server
+    Serve,
+    /// Runs any pending database migrations and registers the configured facilities
+    Migrate,
+    /// Fetches a single reading of each facility, printing the details of each fetch, and stores it unless the facility is closed or another instance is fetching it
+    FetchOnce(FetchOnceArgs),
+    /// Imports historical measurements from a CSV or NDJSON file of timestamps and values
+    Import(ImportArgs),
+    /// Imports the events of an iCalendar file as tagged calendar periods, such as terms or vacations
+    ImportCalendar(ImportCalendarArgs),
+    /// Exports raw measurements as CSV or NDJSON
+    Export(ExportArgs),
+    /// Deletes raw measurements before a day, keeping their daily rollups
+    Prune(PruneArgs),
+    /// Validates the configuration and prints a summary of it
+    CheckConfig,
+    /// Replays stored measurements to measure the accuracy of occupancy predictions
+    Backtest(BacktestArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct FetchOnceArgs {
+    /// Facility to fetch, defaults to all configured facilities
+    #[arg(long)]
+    pub facility: Option<String>,
+    /// Only prints the details of each fetch, without connecting to the database
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    /// Facility to export, defaults to all facilities
+    #[arg(long)]
+    pub facility: Option<String>,
+    /// First day to export, defaults to the first measurement
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last day to export, defaults to today
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+    pub format: ExportFormat,
+    /// File to write to, defaults to standard output
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct PruneArgs {
+    /// Measurements made before the start of this day are deleted
+    #[arg(long)]
+    pub before: NaiveDate,
+    /// Facility to prune, defaults to all facilities
+    #[arg(long)]
+    pub facility: Option<String>,
+    /// Reports the number of measurements that would be deleted without deleting them
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct BacktestArgs {
+    /// Facility to evaluate, defaults to the first configured facility
+    #[arg(long)]
+    pub facility: Option<String>,
+    /// First day to replay, defaults to 28 days before `to`
+    #[arg(long)]
+    pub from: Option<NaiveDate>,
+    /// Last day to replay, defaults to yesterday
+    #[arg(long)]
+    pub to: Option<NaiveDate>,
+    #[arg(long, value_enum, default_value_t = Predictor::Baseline)]
+    pub predictor: Predictor,
+    /// Minutes between predictions
+    #[arg(long, default_value_t = 60)]
+    pub step: u64,
+    /// Hours predicted ahead
+    #[arg(long, default_value_t = 3)]
+    pub hours: u64,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportCalendarArgs {
+    /// Path of the `.ics` file
+    pub file: PathBuf,
+    /// Kind of every period in the file, defaults to the first category of each event
+    #[arg(long)]
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    /// Path of the file
+    pub file: PathBuf,
+    /// Defaults to NDJSON for `.ndjson` and `.jsonl` files and CSV otherwise
+    #[arg(long, value_enum)]
+    pub format: Option<ImportFormat>,
+    /// Facility of rows without a `facility_id`, defaults to the first configured facility
+    #[arg(long)]
+    pub facility: Option<String>,
+    /// Handling of rows at the same time as a stored measurement
+    #[arg(long, value_enum, default_value_t = OnConflict::Skip)]
+    pub on_conflict: OnConflict,
+    /// Validates the file and reports what would be imported without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl Cli {
+    /// Runs the command, serving if none is given
+    pub async fn run(self) -> Result<()> {
+        let config = Config::new()?;
+
+        match self.command.unwrap_or(Command::Serve) {
+            Command::Serve => start(&config).await?.join().await,
+            Command::Migrate => {
+                connect(&config).await?;
+                println!("Database is up to date");
+                Ok(())
+            }
+            Command::FetchOnce(args) => run_fetch_once(&config, args).await,
+            Command::Import(args) => run_import(&config, args).await,
+            Command::ImportCalendar(args) => run_import_calendar(&config, args).await,
+            Command::Export(args) => run_export(&config, args).await,
+            Command::Prune(args) => run_prune(&config, args).await,
+            Command::CheckConfig => run_check_config(&config),
+            Command::Backtest(args) => run_backtest(&config, args).await,
+        }
+    }
+}
+
+/// Connects to the database and runs any pending migrations
+async fn connect(config: &Config) -> Result<Pool<Postgres>> {
+    let db = PgPoolOptions::new()
+        .connect(&config.database_url)
+        .await
+        .wrap_err("Failed to connect to database")?;
+    migrate(&db, config).await?;
+
+    Ok(db)
+}
+
+/// Checks `facility` is configured, if given
+fn ensure_facility(config: &Config, facility: Option<&str>) -> Result<()> {
+    if let Some(facility) = facility {
+        ensure!(
+            config.facility(facility).is_some(),
+            "unknown facility {facility:?}"
+        );
+    }
+
+    Ok(())
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN).and_utc()
+}
+
+async fn run_fetch_once(config: &Config, args: FetchOnceArgs) -> Result<()> {
+    ensure_facility(config, args.facility.as_deref())?;
+
+    let db = if args.dry_run {
+        None
+    } else {
+        Some(connect(config).await?)
+    };
+    let anomalies = AnomalyDetector::from_config(config);
+    let mut failures = 0;
+
+    for facility in config
+        .facilities
+        .iter()
+        .filter(|f| args.facility.as_ref().is_none_or(|id| *id == f.id))
+    {
+        let report = HtmlSource::new(&facility.url, &facility.pattern)?
+            .diagnose()
+            .await;
+        println!("{} ({})", facility.id, facility.name);
+        println!("{report}");
+
+        let Ok(value) = report.result else {
+            failures += 1;
+            continue;
+        };
+
+        let Some(db) = &db else {
+            continue;
+        };
+
+        let reading = Reading {
+            value,
+            measured_at: Utc::now(),
+        };
+
+        // stores only what the running fetcher would, so that readings are not duplicated or made outside opening hours
+        if !facility.hours.is_open(reading.measured_at) {
+            println!("Not storing reading, {} is closed\n", facility.id);
+            continue;
+        }
+        let mut leader = LeaderLock::new(db.clone(), "fetcher", &facility.id);
+        if !leader.poll().await {
+            println!(
+                "Not storing reading, another instance is fetching {}\n",
+                facility.id
+            );
+            continue;
+        }
+
+        store_reading(db, &facility.id, &reading).await?;
+        anomalies
+            .check(db, &facility.id, &facility.hours, &reading)
+            .await?;
+    }
+
+    ensure!(failures == 0, "{failures} fetch(es) failed");
+
+    Ok(())
+}
+
+async fn run_export(config: &Config, args: ExportArgs) -> Result<()> {
+    ensure_facility(config, args.facility.as_deref())?;
+
+    let from = args.from.map_or(DateTime::UNIX_EPOCH, midnight);
+    let to = midnight(args.to.unwrap_or_else(|| Utc::now().date_naive())) + TimeDelta::days(1);
+    ensure!(from < to, "from must not be after to");
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(
+            File::create(path).wrap_err_with(|| format!("Failed to create {}", path.display()))?,
+        ),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(&mut out);
+
+    let db = connect(config).await?;
+    let mut rows = export::measurements(&db, args.facility.as_deref(), from, to);
+
+    let mut buf = args.format.header();
+    while let Some(row) = rows.next().await {
+        args.format.encode(&row?, &mut buf);
+        out.write_all(&buf)?;
+        buf.clear();
+    }
+    out.write_all(&buf)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+async fn run_prune(config: &Config, args: PruneArgs) -> Result<()> {
+    ensure_facility(config, args.facility.as_deref())?;
+    ensure!(
+        args.before <= Utc::now().date_naive(),
+        "before must not be after today"
+    );
+
+    let db = connect(config).await?;
+    let before = midnight(args.before);
+
+    if args.dry_run {
+        let count = sqlx::query_scalar!(
+            r#"
+                SELECT COUNT(*) as "count!"
+                FROM measurements
+                WHERE ($1::text IS NULL OR facility_id = $1) AND measured_at < $2
+            "#,
+            args.facility,
+            before,
+        )
+        .fetch_one(&db)
+        .await?;
+
+        println!("Would delete {count} measurement(s) before {}", args.before);
+        return Ok(());
+    }
+
+    // the rollups of pruned days can no longer be computed afterwards, so any missing are computed first
+    // existing rollups are kept up to date by the rollup task and imports, and may belong to days pruned before
+    for facility in config
+        .facilities
+        .iter()
+        .filter(|f| args.facility.as_ref().is_none_or(|id| *id == f.id))
+    {
+        let rolled_up = sqlx::query_scalar!(
+            "SELECT day FROM daily_rollups WHERE facility_id = $1 AND day < $2",
+            facility.id,
+            args.before,
+        )
+        .fetch_all(&db)
+        .await?;
+
+        rollup::refresh(&db, &facility.id, DateTime::UNIX_EPOCH, &rolled_up).await?;
+    }
+
+    let count = sqlx::query!(
+        "DELETE FROM measurements WHERE ($1::text IS NULL OR facility_id = $1) AND measured_at < $2",
+        args.facility,
+        before,
+    )
+    .execute(&db)
+    .await?
+    .rows_affected();
+
+    println!("Deleted {count} measurement(s) before {}", args.before);
+
+    Ok(())
+}
+
+fn run_check_config(config: &Config) -> Result<()> {
+    println!("Address: {}", config.address);
+    println!("Fetch interval: {}s", config.fetch_interval);
+    println!(
+        "Export: {}",
+        if config.export_token.is_some() {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    for facility in &config.facilities {
+        let regex = Regex::new(&facility.pattern)
+            .wrap_err_with(|| format!("Invalid pattern of facility {:?}", facility.id))?;
+        ensure!(
+            regex.captures_len() >= 2,
+            "Pattern of facility {:?} has no capture group",
+            facility.id
+        );
+
+        println!(
+            "Facility {:?} ({}): {}",
+            facility.id, facility.name, facility.url
+        );
+    }
+
+    println!("Configuration is valid");
+
+    Ok(())
+}
+
+async fn run_backtest(config: &Config, args: BacktestArgs) -> Result<()> {
+    ensure!(args.step > 0, "step must be at least 1 minute");
+    ensure!(
+        (1..=MAX_BACKTEST_HOURS).contains(&args.hours),
+        "hours must be between 1 and {MAX_BACKTEST_HOURS}"
+    );
+
+    let facility = args
+        .facility
+        .unwrap_or_else(|| config.default_facility().id.clone());
+    ensure_facility(config, Some(&facility))?;
+
+    let to = args
+        .to
+        .unwrap_or_else(|| Utc::now().date_naive() - TimeDelta::days(1));
+    let from = args
+        .from
+        .unwrap_or(to - TimeDelta::days(DEFAULT_BACKTEST_DAYS - 1));
+    ensure!(from <= to, "from must not be after to");
+
+    // thousands of small queries are run, each of which would otherwise be JIT compiled
+    let options = config
+        .database_url
+        .parse::<PgConnectOptions>()?
+        .options([("jit", "off")]);
+    let db = PgPoolOptions::new().connect_with(options).await?;
+
+    let report = backtest(
+        &db,
+        &facility,
+        args.predictor,
+        from,
+        to,
+        Duration::from_secs(args.step * 60),
+        Duration::from_secs(args.hours * 60 * 60),
+    )
+    .await?;
+
+    match args.format {
+        OutputFormat::Table => print!("{report}"),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+
+    Ok(())
+}
+
+async fn run_import_calendar(config: &Config, args: ImportCalendarArgs) -> Result<()> {
+    let text = fs::read_to_string(&args.file)
+        .wrap_err_with(|| format!("Failed to read {}", args.file.display()))?;
+    let periods = parse_ics(&text, args.kind.as_deref())?;
+
+    let db = connect(config).await?;
+
+    let count = calendar::import(&db, &periods).await?;
+    println!("Imported {count} calendar periods");
+
+    Ok(())
+}
+
+async fn run_import(config: &Config, args: ImportArgs) -> Result<()> {
+    let facility = args
+        .facility
+        .unwrap_or_else(|| config.default_facility().id.clone());
+    let format = args.format.unwrap_or_else(|| {
+        ImportFormat::from_extension(args.file.extension().and_then(|e| e.to_str()))
+    });
+
+    let text = fs::read_to_string(&args.file)
+        .wrap_err_with(|| format!("Failed to read {}", args.file.display()))?;
+
+    let rows = match import::parse(&text, format, config, &facility) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            for error in errors.iter().take(MAX_PRINTED_ROW_ERRORS) {
+                eprintln!("{error}");
+            }
+            if errors.len() > MAX_PRINTED_ROW_ERRORS {
+                eprintln!("... and {} more", errors.len() - MAX_PRINTED_ROW_ERRORS);
+            }
+            bail!("{} invalid row(s), nothing was imported", errors.len());
+        }
+    };
+
+    let db = connect(config).await?;
+
+    let summary = import::import(&db, &rows, args.on_conflict, args.dry_run).await?;
+    print!("{summary}");
+
+    Ok(())
+}
@@ -1,12 +1,22 @@
 //! App configuration
 
 use {
-    color_eyre::eyre::{Result, WrapErr},
-    config::Environment,
+    crate::{
+        hours::OpeningHours,
+        source::{PATTERN, URL},
+    },
+    color_eyre::eyre::{ensure, Result, WrapErr},
+    config::{Environment, File},
     serde::Deserialize,
-    std::net::SocketAddr,
+    std::{collections::HashSet, net::SocketAddr},
 };
 
+/// Name of the optional configuration file, without extension
+const FILE_NAME: &str = "isthegymbusy";
+
+/// Largest allowed `anomaly_stuck_after`, one week in minutes
+const MAX_ANOMALY_STUCK_AFTER: u64 = 7 * 24 * 60;
+
 /// Configuration parameters
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
@@ -16,23 +26,267 @@ pub struct Config {
     /// Number of seconds between fetching status
     pub fetch_interval: u64,
 
+    /// Number of fetch intervals after which the current status is considered stale
+    #[serde(default = "default_stale_after_intervals")]
+    pub stale_after_intervals: u32,
+
+    /// Maximum number of attempts at fetching status per interval
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: u32,
+
+    /// Number of milliseconds to wait before the first retry, doubled for each subsequent retry
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64,
+
+    /// Fraction of each retry delay that is randomised, between 0 and 1
+    #[serde(default = "default_retry_jitter")]
+    pub retry_jitter: f64,
+
+    /// Percentage points by which a reading must differ from the typical value to be flagged as a spike
+    #[serde(default = "default_anomaly_spike_threshold")]
+    pub anomaly_spike_threshold: u8,
+
+    /// Number of minutes of identical readings during opening hours after which they are flagged as stuck and marked as suspect
+    #[serde(default = "default_anomaly_stuck_after")]
+    pub anomaly_stuck_after: u64,
+
     /// Postgres URL
     pub database_url: String,
 
     /// Sentry ingest URL
     pub sentry_url: String,
+
+    /// Bearer token required by the export routes, which are disabled if unset
+    #[serde(default)]
+    pub export_token: Option<String>,
+
+    /// Facilities to track, the first of which is served by the unscoped routes
+    #[serde(default = "default_facilities")]
+    pub facilities: Vec<FacilityConfig>,
+}
+
+/// Configuration for a single tracked facility
+#[derive(Clone, Debug, Deserialize)]
+pub struct FacilityConfig {
+    /// Identifier used in URLs and the database
+    pub id: String,
+
+    /// Human readable name
+    pub name: String,
+
+    /// URL of the page containing the occupancy
+    pub url: String,
+
+    /// Regex with a single capture group matching the occupancy percentage
+    #[serde(default = "default_pattern")]
+    pub pattern: String,
+
+    /// Opening hours, in their own time zone, during which the occupancy is fetched, defaults to 6:00 to 22:00 UTC every day
+    #[serde(default)]
+    pub hours: OpeningHours,
 }
 
 impl Config {
-    /// Builds a new Config instance from an optional file (the path of which is supplied as a argument) and, with a greater priority, environment variables
+    /// Builds a new Config instance from an optional file (`isthegymbusy.{toml,yaml,json}` in the working directory) and, with a greater priority, environment variables
     pub fn new() -> Result<Self> {
         dotenv::dotenv().ok();
 
-        config::Config::builder()
+        let config: Self = config::Config::builder()
+            .add_source(File::with_name(FILE_NAME).required(false))
             .add_source(Environment::default())
             .build()
             .wrap_err("Failed build configuration")?
             .try_deserialize()
-            .wrap_err("Failed deserialize configuration")
+            .wrap_err("Failed deserialize configuration")?;
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks the values are within their allowed ranges
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.facilities.is_empty(),
+            "At least one facility must be configured"
+        );
+
+        ensure!(
+            self.fetch_interval >= 1,
+            "fetch_interval must be at least 1"
+        );
+
+        ensure!(
+            self.stale_after_intervals >= 1,
+            "stale_after_intervals must be at least 1"
+        );
+
+        ensure!(
+            self.retry_max_attempts >= 1,
+            "retry_max_attempts must be at least 1"
+        );
+
+        // also rejects NaN
+        ensure!(
+            (0.0..=1.0).contains(&self.retry_jitter),
+            "retry_jitter must be between 0 and 1"
+        );
+
+        ensure!(
+            (1..=MAX_ANOMALY_STUCK_AFTER).contains(&self.anomaly_stuck_after),
+            "anomaly_stuck_after must be between 1 and {MAX_ANOMALY_STUCK_AFTER} minutes"
+        );
+
+        let mut ids = HashSet::new();
+        for facility in &self.facilities {
+            ensure!(
+                ids.insert(&facility.id),
+                "Duplicate facility ID {:?}",
+                facility.id
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Gets a configured facility by its ID
+    pub fn facility(&self, id: &str) -> Option<&FacilityConfig> {
+        self.facilities.iter().find(|facility| facility.id == id)
+    }
+
+    /// Gets the facility served by the unscoped routes
+    pub fn default_facility(&self) -> &FacilityConfig {
+        &self.facilities[0]
+    }
+}
+
+fn default_stale_after_intervals() -> u32 {
+    3
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    1000
+}
+
+fn default_retry_jitter() -> f64 {
+    0.5
+}
+
+fn default_anomaly_spike_threshold() -> u8 {
+    30
+}
+
+fn default_anomaly_stuck_after() -> u64 {
+    120
+}
+
+fn default_facilities() -> Vec<FacilityConfig> {
+    vec![FacilityConfig {
+        id: "gym".to_owned(),
+        name: "Gym".to_owned(),
+        url: URL.to_owned(),
+        pattern: default_pattern(),
+        hours: OpeningHours::default(),
+    }]
+}
+
+fn default_pattern() -> String {
+    PATTERN.to_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Valid configuration with the default settings and facilities `gym` and `pool`
+    pub(crate) fn config() -> Config {
+        let facility = |id: &str| FacilityConfig {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            url: URL.to_owned(),
+            pattern: default_pattern(),
+            hours: OpeningHours::default(),
+        };
+
+        Config {
+            address: "127.0.0.1:0".parse().unwrap(),
+            fetch_interval: 60,
+            stale_after_intervals: default_stale_after_intervals(),
+            retry_max_attempts: default_retry_max_attempts(),
+            retry_base_delay_ms: default_retry_base_delay_ms(),
+            retry_jitter: default_retry_jitter(),
+            anomaly_spike_threshold: default_anomaly_spike_threshold(),
+            anomaly_stuck_after: default_anomaly_stuck_after(),
+            database_url: String::new(),
+            sentry_url: String::new(),
+            export_token: None,
+            facilities: vec![facility("gym"), facility("pool")],
+        }
+    }
+
+    #[test]
+    fn accepts_defaults() {
+        assert!(config().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_intervals() {
+        let zero_fetch = Config {
+            fetch_interval: 0,
+            ..config()
+        };
+        let zero_stale = Config {
+            stale_after_intervals: 0,
+            ..config()
+        };
+
+        assert!(zero_fetch.validate().is_err());
+        assert!(zero_stale.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_zero_retry_attempts() {
+        let config = Config {
+            retry_max_attempts: 0,
+            ..config()
+        };
+
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn rejects_jitter_outside_unit_range() {
+        for retry_jitter in [-0.1, 1.1, f64::NAN] {
+            let config = Config {
+                retry_jitter,
+                ..config()
+            };
+
+            assert!(config.validate().is_err(), "accepted {retry_jitter}");
+        }
+    }
+
+    #[test]
+    fn rejects_stuck_after_out_of_range() {
+        for anomaly_stuck_after in [0, MAX_ANOMALY_STUCK_AFTER + 1] {
+            let config = Config {
+                anomaly_stuck_after,
+                ..config()
+            };
+
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_facilities() {
+        let mut config = config();
+        config.facilities[1].id = "gym".to_owned();
+
+        assert!(config.validate().is_err());
     }
 }
@@ -13,6 +13,16 @@ use {
 pub enum Error {
     /// Failed to get gym status information
     StatusRequestFailed,
+    /// Unknown facility {0:?}
+    UnknownFacility(String),
+    /// Invalid history query: {0}
+    InvalidHistoryQuery(String),
+    /// Invalid parameters: {0}
+    InvalidParameters(String),
+    /// Missing or invalid bearer token
+    Unauthorized,
+    /// Database error
+    Database(#[from] sqlx::Error),
 }
 
 impl IntoResponse for Error {
@@ -21,6 +31,10 @@ impl IntoResponse for Error {
 
         let status_code = match self {
             Error::StatusRequestFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::UnknownFacility(_) => StatusCode::NOT_FOUND,
+            Error::InvalidHistoryQuery(_) | Error::InvalidParameters(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized => StatusCode::UNAUTHORIZED,
+            Error::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         (status_code, self.to_string()).into_response()
@@ -0,0 +1,102 @@
+//! Bulk export of raw measurements, shared by the export route and command
+
+use {
+    chrono::{DateTime, Utc},
+    clap::ValueEnum,
+    serde::{Deserialize, Serialize},
+    sqlx::{Pool, Postgres},
+    std::pin::Pin,
+    tokio_stream::Stream,
+};
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Ndjson,
+}
+
+/// Single exported measurement
+#[derive(Debug, Serialize)]
+pub struct ExportRow {
+    pub facility_id: String,
+    pub measured_at: DateTime<Utc>,
+    pub value: i16,
+    pub suspect: bool,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Ndjson => "ndjson",
+        }
+    }
+
+    /// Encodes the lines preceding the rows
+    pub fn header(self) -> Vec<u8> {
+        match self {
+            Self::Csv => b"facility_id,measured_at,value,suspect\n".to_vec(),
+            Self::Ndjson => vec![],
+        }
+    }
+
+    /// Encodes a row as a single line
+    pub fn encode(self, row: &ExportRow, buf: &mut Vec<u8>) {
+        match self {
+            Self::Csv => buf.extend(
+                format!(
+                    "{},{},{},{}\n",
+                    csv_field(&row.facility_id),
+                    row.measured_at.to_rfc3339(),
+                    row.value,
+                    row.suspect
+                )
+                .bytes(),
+            ),
+            Self::Ndjson => {
+                serde_json::to_writer(&mut *buf, row).unwrap();
+                buf.push(b'\n');
+            }
+        }
+    }
+}
+
+/// Streams the measurements of a facility, or all facilities, from `from` inclusive to `to` exclusive, ordered by facility and time
+pub fn measurements<'a>(
+    db: &'a Pool<Postgres>,
+    facility: Option<&'a str>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Pin<Box<dyn Stream<Item = Result<ExportRow, sqlx::Error>> + Send + 'a>> {
+    sqlx::query_as!(
+        ExportRow,
+        r#"
+            SELECT facility_id, measured_at, value, suspect
+            FROM measurements
+            WHERE ($1::text IS NULL OR facility_id = $1) AND measured_at >= $2 AND measured_at < $3
+            ORDER BY facility_id, measured_at
+        "#,
+        facility,
+        from,
+        to,
+    )
+    .fetch(db)
+}
+
+/// Quotes a CSV field if it contains special characters
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,289 @@
+//! Short-term occupancy forecasting
+//!
+//! Forecasts start from the seasonal naive prediction, the typical value of each slot on the same day of the week over the past few weeks of the same calendar period kind, which is then corrected towards the current reading and its recent trend. The correction decays with the forecast horizon, so distant slots fall back to the typical value.
+//!
+//! Inputs are only ever loaded from measurements made before the forecast origin, so forecasts can be replayed against stored data, see `crate::backtest`.
+
+use {
+    crate::{
+        error::Error,
+        routes::history::{Aggregate, HistoryQuery},
+    },
+    chrono::{DateTime, DurationRound, TimeDelta, Utc},
+    sqlx::{Pool, Postgres},
+    std::time::Duration,
+};
+
+/// Size of each forecast slot
+pub const INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Default length of time to forecast
+pub const DEFAULT_HORIZON: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// Number of past weeks the seasonal prediction is averaged over
+const WEEKS: u32 = 4;
+
+/// Fraction of the lowest and highest measurements ignored in the seasonal prediction
+const TRIM: f64 = 0.1;
+
+/// Window of recent measurements used for the current reading and trend
+const RECENT_WINDOW: TimeDelta = TimeDelta::hours(1);
+
+/// Factor by which the correction towards the current reading decays per hour
+const DECAY_PER_HOUR: f64 = 0.5;
+
+const WEEK: TimeDelta = TimeDelta::weeks(1);
+
+/// Everything a forecast is computed from
+#[derive(Debug, Clone)]
+pub struct ForecastInputs {
+    /// Time the forecast is made at
+    pub origin: DateTime<Utc>,
+    /// Start of the first forecast slot, the slot after the one containing `origin`
+    pub first: DateTime<Utc>,
+    /// Seasonal prediction for the slot containing `origin`
+    pub seasonal_now: Option<f64>,
+    /// Seasonal prediction for each forecast slot
+    pub seasonal: Vec<Option<f64>>,
+    /// Measurements in the window before `origin`, oldest first
+    pub recent: Vec<(DateTime<Utc>, f64)>,
+}
+
+/// Predicted occupancy of consecutive slots
+#[derive(Debug, Clone)]
+pub struct Forecast {
+    /// Start of the first slot
+    pub first: DateTime<Utc>,
+    pub interval: Duration,
+    /// Predicted occupancy of each slot, oldest first, or `None` if there is nothing to predict from
+    pub values: Vec<Option<f64>>,
+}
+
+/// Loads the inputs of a forecast for `horizon` after `origin`, using only measurements made before `origin`
+pub async fn load(
+    db: &Pool<Postgres>,
+    facility: &str,
+    origin: DateTime<Utc>,
+    horizon: Duration,
+) -> Result<ForecastInputs, Error> {
+    let interval = TimeDelta::from_std(INTERVAL).unwrap();
+    let current = origin.duration_trunc(interval).unwrap();
+    let slots = horizon.as_secs().div_ceil(INTERVAL.as_secs()) as i32;
+
+    // slots of the previous weeks, starting with the one containing the origin
+    let seasonal = HistoryQuery {
+        from: current - WEEK,
+        to: current - WEEK + interval * slots,
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        periods: WEEKS,
+        period: WEEK.to_std().unwrap(),
+        trim: TRIM,
+        exclude_zero: false,
+        exclude_suspect: true,
+        same_period_as: Some(origin.date_naive()),
+    }
+    .fetch(db, facility)
+    .await?;
+
+    let mut seasonal = seasonal.values.into_iter().rev();
+    let seasonal_now = seasonal.next().flatten();
+
+    let recent = sqlx::query!(
+        r#"
+            SELECT measured_at, value
+            FROM measurements
+            WHERE facility_id = $1 AND measured_at > $2 AND measured_at <= $3 AND NOT suspect
+            ORDER BY measured_at
+        "#,
+        facility,
+        origin - RECENT_WINDOW,
+        origin,
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| (row.measured_at, f64::from(row.value)))
+    .collect();
+
+    Ok(ForecastInputs {
+        origin,
+        first: current + interval,
+        seasonal_now,
+        seasonal: seasonal.collect(),
+        recent,
+    })
+}
+
+/// Computes a forecast from its inputs
+pub fn predict(inputs: &ForecastInputs) -> Forecast {
+    let interval = TimeDelta::from_std(INTERVAL).unwrap();
+    let current = inputs.recent.last().map(|(_, value)| *value);
+
+    // difference between the current reading and trend and what is typical at this time
+    let (offset, slope_offset) = match (current, inputs.seasonal_now) {
+        (Some(current), Some(seasonal_now)) => {
+            let seasonal_slope = inputs
+                .seasonal
+                .first()
+                .copied()
+                .flatten()
+                .map_or(0.0, |next| (next - seasonal_now) / hours(interval));
+
+            let slope_offset = trend(&inputs.recent).map_or(0.0, |slope| slope - seasonal_slope);
+
+            (current - seasonal_now, slope_offset)
+        }
+        _ => (0.0, 0.0),
+    };
+
+    let values = inputs
+        .seasonal
+        .iter()
+        .enumerate()
+        .map(|(i, seasonal)| {
+            let midpoint = inputs.first + interval * i as i32 + interval / 2;
+            let h = hours(midpoint - inputs.origin);
+            let decay = DECAY_PER_HOUR.powf(h);
+
+            let base = seasonal.or(current)?;
+            Some((base + (offset + slope_offset * h) * decay).max(0.0))
+        })
+        .collect();
+
+    Forecast {
+        first: inputs.first,
+        interval: INTERVAL,
+        values,
+    }
+}
+
+/// Forecasts the occupancy of a facility for `horizon` after `origin`
+pub async fn forecast(
+    db: &Pool<Postgres>,
+    facility: &str,
+    origin: DateTime<Utc>,
+    horizon: Duration,
+) -> Result<Forecast, Error> {
+    Ok(predict(&load(db, facility, origin, horizon).await?))
+}
+
+/// Least squares slope of measurements in percent per hour
+fn trend(measurements: &[(DateTime<Utc>, f64)]) -> Option<f64> {
+    let (first, _) = measurements.first()?;
+    let points = measurements
+        .iter()
+        .map(|(t, v)| (hours(*t - *first), *v))
+        .collect::<Vec<_>>();
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+
+    let covariance = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum::<f64>();
+    let variance = points
+        .iter()
+        .map(|(x, _)| (x - mean_x).powi(2))
+        .sum::<f64>();
+
+    (variance > 0.0).then(|| covariance / variance)
+}
+
+fn hours(delta: TimeDelta) -> f64 {
+    delta.num_seconds() as f64 / 3600.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn origin() -> DateTime<Utc> {
+        DateTime::from_timestamp(1_792_000_000, 0).unwrap()
+    }
+
+    fn minutes_before(minutes: i64, value: f64) -> (DateTime<Utc>, f64) {
+        (origin() - TimeDelta::minutes(minutes), value)
+    }
+
+    fn inputs(
+        seasonal_now: Option<f64>,
+        seasonal: Vec<Option<f64>>,
+        recent: Vec<(DateTime<Utc>, f64)>,
+    ) -> ForecastInputs {
+        let interval = TimeDelta::from_std(INTERVAL).unwrap();
+
+        ForecastInputs {
+            origin: origin(),
+            first: origin().duration_trunc(interval).unwrap() + interval,
+            seasonal_now,
+            seasonal,
+            recent,
+        }
+    }
+
+    #[test]
+    fn trend_of_single_point_is_none() {
+        assert_eq!(trend(&[minutes_before(5, 40.0)]), None);
+        assert_eq!(trend(&[]), None);
+    }
+
+    #[test]
+    fn trend_without_time_variance_is_none() {
+        assert_eq!(
+            trend(&[minutes_before(5, 40.0), minutes_before(5, 60.0)]),
+            None
+        );
+    }
+
+    #[test]
+    fn trend_is_slope_per_hour() {
+        let slope = trend(&[
+            minutes_before(60, 20.0),
+            minutes_before(30, 25.0),
+            minutes_before(0, 30.0),
+        ])
+        .unwrap();
+
+        assert!((slope - 10.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn forecast_decays_towards_seasonal_value() {
+        // 12 hours of slots, with the current reading 30 points above typical and no trend
+        let forecast = predict(&inputs(
+            Some(50.0),
+            vec![Some(50.0); 48],
+            vec![minutes_before(30, 80.0), minutes_before(0, 80.0)],
+        ));
+
+        let first = forecast.values[0].unwrap();
+        let last = forecast.values[47].unwrap();
+        assert!(first > 60.0 && first < 80.0, "first slot {first}");
+        assert!((last - 50.0).abs() < 0.01, "last slot {last}");
+        assert!(forecast
+            .values
+            .windows(2)
+            .all(|pair| pair[0].unwrap() >= pair[1].unwrap()));
+    }
+
+    #[test]
+    fn missing_seasonal_value_falls_back_to_current() {
+        let forecast = predict(&inputs(
+            None,
+            vec![None, Some(40.0)],
+            vec![minutes_before(0, 70.0)],
+        ));
+
+        assert_eq!(forecast.values, vec![Some(70.0), Some(40.0)]);
+    }
+
+    #[test]
+    fn nothing_to_predict_from_is_none() {
+        let forecast = predict(&inputs(None, vec![None, None], vec![]));
+
+        assert_eq!(forecast.values, vec![None, None]);
+    }
+}
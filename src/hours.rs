@@ -0,0 +1,380 @@
+//! Opening hours of facilities
+//!
+//! Hours are given in the facility's time zone, UTC unless set, as `HH:MM-HH:MM`, `open` for the whole day or `closed`. A closing time of `00:00` means midnight at the end of the day, so facilities cannot stay open past midnight. Dates are the facility's local dates.
+
+use {
+    chrono::{
+        DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Utc, Weekday,
+    },
+    chrono_tz::Tz,
+    serde::Deserialize,
+};
+
+/// Days of the week, Monday first
+pub const WEEKDAYS: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// Opening and closing time on a single day
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Hours {
+    Closed,
+    Open { opens: NaiveTime, closes: NaiveTime },
+}
+
+/// Weekly opening hours, with exceptions for specific dates such as holidays
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OpeningHours {
+    pub mon: Hours,
+    pub tue: Hours,
+    pub wed: Hours,
+    pub thu: Hours,
+    pub fri: Hours,
+    pub sat: Hours,
+    pub sun: Hours,
+    /// Hours replacing those of the day of the week on specific dates
+    pub exceptions: Vec<OpeningException>,
+    /// IANA time zone of the hours and dates, such as `Europe/London`, defaults to UTC
+    pub timezone: Tz,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpeningException {
+    pub date: NaiveDate,
+    pub hours: Hours,
+}
+
+impl Hours {
+    /// Gets the time the facility opens and closes on `date` in `timezone`, or `None` if it is closed
+    pub fn range(self, date: NaiveDate, timezone: Tz) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let Self::Open { opens, closes } = self else {
+            return None;
+        };
+
+        let closes = if closes == NaiveTime::MIN {
+            date.succ_opt()?.and_time(NaiveTime::MIN)
+        } else {
+            date.and_time(closes)
+        };
+
+        Some((
+            local(timezone, date.and_time(opens)),
+            local(timezone, closes),
+        ))
+    }
+}
+
+/// Converts a local time to UTC, taking the earlier of repeated times and moving skipped times forward by an hour
+fn local(timezone: Tz, time: NaiveDateTime) -> DateTime<Utc> {
+    timezone
+        .from_local_datetime(&time)
+        .earliest()
+        .or_else(|| {
+            timezone
+                .from_local_datetime(&(time + TimeDelta::hours(1)))
+                .earliest()
+        })
+        .map_or_else(|| time.and_utc(), |time| time.to_utc())
+}
+
+impl OpeningHours {
+    /// Gets the regular hours of a day of the week
+    pub fn weekday(&self, weekday: Weekday) -> Hours {
+        match weekday {
+            Weekday::Mon => self.mon,
+            Weekday::Tue => self.tue,
+            Weekday::Wed => self.wed,
+            Weekday::Thu => self.thu,
+            Weekday::Fri => self.fri,
+            Weekday::Sat => self.sat,
+            Weekday::Sun => self.sun,
+        }
+    }
+
+    /// Gets the hours on a date, taking exceptions into account
+    pub fn on(&self, date: NaiveDate) -> Hours {
+        self.exceptions
+            .iter()
+            .find(|exception| exception.date == date)
+            .map_or_else(|| self.weekday(date.weekday()), |exception| exception.hours)
+    }
+
+    /// Gets the local date at `at`
+    pub fn date(&self, at: DateTime<Utc>) -> NaiveDate {
+        at.with_timezone(&self.timezone).date_naive()
+    }
+
+    /// Gets the time the facility opens and closes on `date`, taking exceptions into account, or `None` if it is closed
+    pub fn range(&self, date: NaiveDate) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        self.on(date).range(date, self.timezone)
+    }
+
+    /// Gets the start of `date`
+    pub fn midnight(&self, date: NaiveDate) -> DateTime<Utc> {
+        local(self.timezone, date.and_time(NaiveTime::MIN))
+    }
+
+    /// Whether the facility is open at `at`
+    pub fn is_open(&self, at: DateTime<Utc>) -> bool {
+        self.range(self.date(at))
+            .is_some_and(|(opens, closes)| opens <= at && at < closes)
+    }
+
+    /// Whether the facility is open for the whole of `from` to `to`, exclusive
+    pub fn is_open_throughout(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        self.range(self.date(from))
+            .is_some_and(|(opens, closes)| opens <= from && from <= to && to <= closes)
+    }
+
+    /// Whether the facility is closed for the whole of `from` to `to`, exclusive
+    pub fn is_closed_throughout(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> bool {
+        !self
+            .date(from)
+            .iter_days()
+            .take_while(|date| self.midnight(*date) < to)
+            .any(|date| {
+                self.range(date)
+                    .is_some_and(|(opens, closes)| opens < to && from < closes)
+            })
+    }
+
+    /// Gets the total time the facility is open from `from` to `to`
+    pub fn open_time(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> TimeDelta {
+        self.date(from)
+            .iter_days()
+            .take_while(|date| self.midnight(*date) < to)
+            .filter_map(|date| self.range(date))
+            .map(|(opens, closes)| (closes.min(to) - opens.max(from)).max(TimeDelta::zero()))
+            .sum()
+    }
+
+    /// Gets the range of `hours` on `date`, or the span of the regular hours if they are closed
+    pub fn window(&self, date: NaiveDate, hours: Hours) -> (DateTime<Utc>, DateTime<Utc>) {
+        hours
+            .range(date, self.timezone)
+            .unwrap_or_else(|| self.span(date))
+    }
+
+    /// Gets the earliest opening time to the latest closing time of the regular hours on `date`, or the whole day if the facility is never open
+    pub fn span(&self, date: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        let midnight = self.midnight(date);
+
+        [
+            self.mon, self.tue, self.wed, self.thu, self.fri, self.sat, self.sun,
+        ]
+        .into_iter()
+        .filter_map(|hours| hours.range(date, self.timezone))
+        .map(|(opens, closes)| (opens - midnight, closes - midnight))
+        .reduce(|(a_opens, a_closes), (b_opens, b_closes)| {
+            (a_opens.min(b_opens), a_closes.max(b_closes))
+        })
+        .map_or(
+            (
+                midnight,
+                date.succ_opt()
+                    .map_or(midnight + TimeDelta::days(1), |next| self.midnight(next)),
+            ),
+            |(opens, closes)| (midnight + opens, midnight + closes),
+        )
+    }
+
+    /// Gets the regular hours without any exceptions
+    pub fn regular(&self) -> Self {
+        Self {
+            exceptions: vec![],
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for Hours {
+    /// 6:00 to 22:00
+    fn default() -> Self {
+        Self::Open {
+            opens: NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+            closes: NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
+        }
+    }
+}
+
+impl TryFrom<String> for Hours {
+    type Error = String;
+
+    fn try_from(hours: String) -> Result<Self, String> {
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time.trim(), "%H:%M")
+                .map_err(|_| format!("invalid time {time:?}, expected HH:MM"))
+        };
+
+        match hours.trim() {
+            "closed" => Ok(Self::Closed),
+            "open" => Ok(Self::Open {
+                opens: NaiveTime::MIN,
+                closes: NaiveTime::MIN,
+            }),
+            range => {
+                let (opens, closes) = range.split_once('-').ok_or_else(|| {
+                    format!("invalid hours {range:?}, expected HH:MM-HH:MM, open or closed")
+                })?;
+                let (opens, closes) = (time(opens)?, time(closes)?);
+
+                if closes != NaiveTime::MIN && closes <= opens {
+                    return Err(format!(
+                        "closing time of {range:?} is not after opening time"
+                    ));
+                }
+
+                Ok(Self::Open { opens, closes })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, m, 0).unwrap()
+    }
+
+    fn parse(hours: &str) -> Result<Hours, String> {
+        Hours::try_from(hours.to_owned())
+    }
+
+    #[test]
+    fn parses_range() {
+        assert_eq!(
+            parse(" 06:30 - 22:00 "),
+            Ok(Hours::Open {
+                opens: time(6, 30),
+                closes: time(22, 0),
+            })
+        );
+    }
+
+    #[test]
+    fn parses_open_and_closed() {
+        assert_eq!(parse("closed"), Ok(Hours::Closed));
+        assert_eq!(
+            parse("open"),
+            Ok(Hours::Open {
+                opens: NaiveTime::MIN,
+                closes: NaiveTime::MIN,
+            })
+        );
+    }
+
+    #[test]
+    fn midnight_closes_at_end_of_day() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let (opens, closes) = parse("18:00-00:00").unwrap().range(date, Tz::UTC).unwrap();
+
+        assert_eq!(opens, date.and_time(time(18, 0)).and_utc());
+        assert_eq!(
+            closes,
+            date.succ_opt().unwrap().and_time(NaiveTime::MIN).and_utc()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_hours() {
+        assert!(parse("22:00-06:00").is_err());
+        assert!(parse("10:00-10:00").is_err());
+        assert!(parse("6am-10pm").is_err());
+        assert!(parse("06:00").is_err());
+        assert!(parse("").is_err());
+    }
+
+    #[test]
+    fn open_time_skips_closed_hours() {
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+        let hours = OpeningHours::default();
+        let at = |date: NaiveDate, h, m| date.and_time(time(h, m)).and_utc();
+        let next = date.succ_opt().unwrap();
+
+        assert_eq!(
+            hours.open_time(at(date, 12, 0), at(date, 12, 30)),
+            TimeDelta::minutes(30)
+        );
+        assert_eq!(
+            hours.open_time(at(date, 21, 0), at(next, 7, 0)),
+            TimeDelta::hours(2)
+        );
+        assert_eq!(
+            hours.open_time(at(date, 23, 0), at(next, 5, 0)),
+            TimeDelta::zero()
+        );
+    }
+
+    #[test]
+    fn hours_follow_time_zone() {
+        let hours = OpeningHours {
+            timezone: chrono_tz::Europe::London,
+            ..OpeningHours::default()
+        };
+        let summer = NaiveDate::from_ymd_opt(2026, 7, 1).unwrap();
+        let winter = NaiveDate::from_ymd_opt(2026, 12, 1).unwrap();
+
+        // 6:00 BST is 5:00 UTC
+        assert_eq!(
+            hours.range(summer),
+            Some((
+                summer.and_time(time(5, 0)).and_utc(),
+                summer.and_time(time(21, 0)).and_utc()
+            ))
+        );
+        assert!(hours.is_open(summer.and_time(time(5, 30)).and_utc()));
+        assert!(!hours.is_open(summer.and_time(time(21, 30)).and_utc()));
+        assert!(!hours.is_open(winter.and_time(time(5, 30)).and_utc()));
+        assert!(hours.is_open(winter.and_time(time(21, 30)).and_utc()));
+    }
+
+    #[test]
+    fn dates_are_local() {
+        let hours = OpeningHours {
+            timezone: chrono_tz::Asia::Tokyo,
+            ..OpeningHours::default()
+        };
+        let date = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap();
+
+        // 23:00 UTC is 8:00 the next day in Tokyo
+        let at = date.and_time(time(23, 0)).and_utc();
+        assert_eq!(hours.date(at), date.succ_opt().unwrap());
+        assert!(hours.is_open(at));
+    }
+
+    #[test]
+    fn deserializes_time_zone() {
+        let hours: OpeningHours =
+            serde_json::from_str(r#"{"timezone": "Europe/London", "sun": "closed"}"#).unwrap();
+
+        assert_eq!(hours.timezone, chrono_tz::Europe::London);
+        assert_eq!(hours.sun, Hours::Closed);
+        assert_eq!(OpeningHours::default().timezone, Tz::UTC);
+    }
+
+    #[test]
+    fn exceptions_replace_weekday() {
+        let date = NaiveDate::from_ymd_opt(2026, 12, 25).unwrap();
+        let hours = OpeningHours {
+            exceptions: vec![OpeningException {
+                date,
+                hours: Hours::Closed,
+            }],
+            ..OpeningHours::default()
+        };
+
+        assert_eq!(hours.on(date), Hours::Closed);
+        assert_eq!(hours.on(date.succ_opt().unwrap()), Hours::default());
+        assert!(!hours.is_open(date.and_time(time(12, 0)).and_utc()));
+    }
+}
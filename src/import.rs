@@ -0,0 +1,654 @@
+//! Bulk import of historical measurements
+//!
+//! Rows of `(timestamp, value)` are read from CSV or newline delimited JSON, such as the output of the export route, validated as a whole and then written in a single transaction, so a failed import leaves the stored measurements unchanged.
+
+use {
+    crate::{config::Config, rollup},
+    chrono::{DateTime, NaiveDate, NaiveDateTime, Utc},
+    clap::ValueEnum,
+    serde::Deserialize,
+    sqlx::{Pool, Postgres},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+        fmt,
+    },
+};
+
+/// Number of rows written per statement
+const BATCH_SIZE: usize = 5_000;
+
+/// Largest valid occupancy percentage, well clear of the sentinel values of the binary history format
+const MAX_VALUE: u8 = 100;
+
+/// Encoding of an import file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// Comma separated values, with an optional header naming the `timestamp` (or `measured_at`), `value`, `facility_id` and `suspect` columns, otherwise a timestamp and value per line
+    Csv,
+    /// Newline delimited JSON objects with the same fields as the CSV header
+    Ndjson,
+}
+
+/// Handling of rows at the same time as a stored measurement of the same facility
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Keeps the stored measurement
+    Skip,
+    /// Replaces the stored measurement
+    Overwrite,
+    /// Aborts the import without writing anything
+    Fail,
+}
+
+/// Single validated row of an import file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportRow {
+    /// Line of the file the row was read from, starting at 1
+    pub line: usize,
+    pub facility_id: String,
+    pub measured_at: DateTime<Utc>,
+    pub value: u8,
+    pub suspect: bool,
+}
+
+#[derive(Debug, Clone, thiserror::Error, displaydoc::Display)]
+/// Line {line}: {reason}
+pub struct RowError {
+    pub line: usize,
+    pub reason: String,
+}
+
+/// Error occurred while writing imported measurements
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum ImportError {
+    /// {0} row(s) conflict with stored measurements, the first on line {1}
+    Conflict(usize, usize),
+    /// Database error
+    Database(#[from] sqlx::Error),
+}
+
+/// Outcome of an import, or of what an import would do in a dry run
+#[derive(Debug, Clone, Default)]
+pub struct ImportSummary {
+    pub dry_run: bool,
+    /// Number of valid rows read
+    pub rows: usize,
+    /// Number of rows without a stored measurement at the same time
+    pub inserted: usize,
+    /// Number of rows replacing stored measurements
+    pub overwritten: usize,
+    /// Number of rows skipped in favour of stored measurements
+    pub skipped: usize,
+    /// Earliest and latest time of the rows of each facility
+    pub ranges: BTreeMap<String, (DateTime<Utc>, DateTime<Utc>)>,
+    /// Days of each facility whose measurements were pruned, whose rollups are kept rather than recomputed from the imported rows alone
+    pub pruned_days: BTreeMap<String, Vec<NaiveDate>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRow {
+    #[serde(alias = "measured_at")]
+    timestamp: JsonTimestamp,
+    value: i64,
+    facility_id: Option<String>,
+    #[serde(default)]
+    suspect: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum JsonTimestamp {
+    Unix(i64),
+    Text(String),
+}
+
+impl ImportFormat {
+    /// Guesses the format from a file extension, defaulting to CSV
+    pub fn from_extension(extension: Option<&str>) -> Self {
+        match extension {
+            Some("ndjson" | "jsonl") => Self::Ndjson,
+            _ => Self::Csv,
+        }
+    }
+}
+
+/// Parses and validates all rows of an import file, using `default_facility` for rows without a facility, returning every invalid row on failure
+pub fn parse(
+    text: &str,
+    format: ImportFormat,
+    config: &Config,
+    default_facility: &str,
+) -> Result<Vec<ImportRow>, Vec<RowError>> {
+    let mut rows = vec![];
+    let mut errors = vec![];
+    // line of the first row of each facility and time, to reject duplicates
+    let mut seen = HashMap::new();
+    let now = Utc::now();
+
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    let raw = match format {
+        ImportFormat::Csv => csv_rows(lines),
+        ImportFormat::Ndjson => lines.map(|(line, text)| (line, json_row(text))).collect(),
+    };
+
+    for (line, row) in raw {
+        let row = row.and_then(|(facility_id, measured_at, value, suspect)| {
+            let facility_id = facility_id.unwrap_or_else(|| default_facility.to_owned());
+
+            if config.facility(&facility_id).is_none() {
+                return Err(format!("unknown facility {facility_id:?}"));
+            }
+            if measured_at > now {
+                return Err(format!("timestamp {measured_at} is in the future"));
+            }
+            let value = u8::try_from(value)
+                .ok()
+                .filter(|value| *value <= MAX_VALUE)
+                .ok_or_else(|| format!("value {value} must be between 0 and {MAX_VALUE}"))?;
+
+            if let Some(first) = seen.insert((facility_id.clone(), measured_at), line) {
+                return Err(format!("duplicate of line {first}"));
+            }
+
+            Ok(ImportRow {
+                line,
+                facility_id,
+                measured_at,
+                value,
+                suspect,
+            })
+        });
+
+        match row {
+            Ok(row) => rows.push(row),
+            Err(reason) => errors.push(RowError { line, reason }),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(rows)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Writes rows to the database in a single transaction, or only counts what would be written if `dry_run`, then refreshes the affected rollups
+pub async fn import(
+    db: &Pool<Postgres>,
+    rows: &[ImportRow],
+    on_conflict: OnConflict,
+    dry_run: bool,
+) -> Result<ImportSummary, ImportError> {
+    let mut summary = ImportSummary {
+        dry_run,
+        rows: rows.len(),
+        ..Default::default()
+    };
+
+    let mut facilities = BTreeMap::<&str, Vec<&ImportRow>>::new();
+    for row in rows {
+        facilities.entry(&row.facility_id).or_default().push(row);
+    }
+
+    let mut tx = db.begin().await?;
+
+    // conflicts of every facility are found before writing anything, so that a failure reports all of them
+    let mut conflicts = HashMap::new();
+    for (facility_id, rows) in &facilities {
+        let timestamps = rows.iter().map(|row| row.measured_at).collect::<Vec<_>>();
+
+        let stored = sqlx::query_scalar!(
+            "SELECT measured_at FROM measurements WHERE facility_id = $1 AND measured_at = ANY($2)",
+            facility_id,
+            &timestamps,
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        conflicts.insert(*facility_id, stored);
+    }
+
+    if on_conflict == OnConflict::Fail {
+        let first = rows
+            .iter()
+            .filter(|row| conflicts[row.facility_id.as_str()].contains(&row.measured_at))
+            .map(|row| row.line)
+            .min();
+
+        if let Some(first) = first {
+            let count = conflicts.values().map(HashSet::len).sum();
+            return Err(ImportError::Conflict(count, first));
+        }
+    }
+
+    for (facility_id, rows) in &facilities {
+        let (timestamps, values, suspects): (Vec<_>, Vec<_>, Vec<_>) = rows
+            .iter()
+            .map(|row| (row.measured_at, i16::from(row.value), row.suspect))
+            .collect();
+
+        let conflicts = conflicts[facility_id].len();
+        summary.inserted += rows.len() - conflicts;
+        match on_conflict {
+            OnConflict::Overwrite => summary.overwritten += conflicts,
+            OnConflict::Skip | OnConflict::Fail => summary.skipped += conflicts,
+        }
+
+        let earliest = timestamps.iter().min().copied().unwrap();
+        let latest = timestamps.iter().max().copied().unwrap();
+        summary
+            .ranges
+            .insert((*facility_id).to_owned(), (earliest, latest));
+
+        // checked before writing, as afterwards the days have measurements again
+        let days = timestamps
+            .iter()
+            .map(|t| t.date_naive())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let pruned_days = sqlx::query_scalar!(
+            r#"
+                SELECT day
+                FROM daily_rollups
+                WHERE facility_id = $1 AND day = ANY($2) AND NOT EXISTS (
+                    SELECT FROM measurements
+                    WHERE
+                        measurements.facility_id = $1 AND
+                        measured_at >= day AT TIME ZONE 'UTC' AND
+                        measured_at < (day + 1) AT TIME ZONE 'UTC'
+                )
+                ORDER BY day
+            "#,
+            facility_id,
+            &days,
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        if !pruned_days.is_empty() {
+            summary
+                .pruned_days
+                .insert((*facility_id).to_owned(), pruned_days);
+        }
+
+        if dry_run {
+            continue;
+        }
+
+        for ((timestamps, values), suspects) in timestamps
+            .chunks(BATCH_SIZE)
+            .zip(values.chunks(BATCH_SIZE))
+            .zip(suspects.chunks(BATCH_SIZE))
+        {
+            match on_conflict {
+                OnConflict::Skip => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO measurements (facility_id, measured_at, value, suspect)
+                        SELECT $1, * FROM UNNEST($2::timestamptz[], $3::smallint[], $4::bool[])
+                        ON CONFLICT (facility_id, measured_at) DO NOTHING
+                    "#,
+                        facility_id,
+                        timestamps,
+                        values,
+                        suspects,
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+                OnConflict::Overwrite => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO measurements (facility_id, measured_at, value, suspect)
+                        SELECT $1, * FROM UNNEST($2::timestamptz[], $3::smallint[], $4::bool[])
+                        ON CONFLICT (facility_id, measured_at) DO UPDATE SET
+                            value = EXCLUDED.value,
+                            suspect = EXCLUDED.suspect
+                    "#,
+                        facility_id,
+                        timestamps,
+                        values,
+                        suspects,
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+                // conflicts were checked above, any made since by another instance fail the transaction
+                OnConflict::Fail => {
+                    sqlx::query!(
+                        r#"
+                        INSERT INTO measurements (facility_id, measured_at, value, suspect)
+                        SELECT $1, * FROM UNNEST($2::timestamptz[], $3::smallint[], $4::bool[])
+                    "#,
+                        facility_id,
+                        timestamps,
+                        values,
+                        suspects,
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+            };
+        }
+    }
+
+    if dry_run {
+        tx.rollback().await?;
+        return Ok(summary);
+    }
+
+    tx.commit().await?;
+
+    for (facility_id, (earliest, _)) in &summary.ranges {
+        let pruned_days = summary
+            .pruned_days
+            .get(facility_id)
+            .map_or(&[][..], Vec::as_slice);
+
+        rollup::refresh(db, facility_id, *earliest, pruned_days).await?;
+    }
+
+    Ok(summary)
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.dry_run {
+            "Would import"
+        } else {
+            "Imported"
+        };
+
+        writeln!(f, "{verb} {} row(s)", self.rows)?;
+        writeln!(f, "{:>12} {}", "inserted", self.inserted)?;
+        writeln!(f, "{:>12} {}", "overwritten", self.overwritten)?;
+        writeln!(f, "{:>12} {}", "skipped", self.skipped)?;
+
+        for (facility_id, (earliest, latest)) in &self.ranges {
+            writeln!(f, "{facility_id}: {earliest} to {latest}")?;
+        }
+
+        for (facility_id, days) in &self.pruned_days {
+            writeln!(
+                f,
+                "{facility_id}: kept rollups of {} pruned day(s), which are not recomputed from the imported rows",
+                days.len()
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+type RawRow = Result<(Option<String>, DateTime<Utc>, i64, bool), String>;
+
+/// Positions of the fields of CSV rows
+#[derive(Debug, Clone, Copy)]
+struct CsvColumns {
+    timestamp: usize,
+    value: usize,
+    facility_id: Option<usize>,
+    suspect: Option<usize>,
+}
+
+/// Reads CSV lines, using the first as the header if it does not start with a timestamp
+fn csv_rows<'a>(mut lines: impl Iterator<Item = (usize, &'a str)>) -> Vec<(usize, RawRow)> {
+    let Some((first_line, first)) = lines.next() else {
+        return vec![];
+    };
+
+    let header = csv_fields(first);
+
+    if header.first().is_some_and(|field| timestamp(field).is_ok()) {
+        let columns = CsvColumns {
+            timestamp: 0,
+            value: 1,
+            facility_id: None,
+            suspect: None,
+        };
+
+        return [(first_line, first)]
+            .into_iter()
+            .chain(lines)
+            .map(|(line, text)| (line, csv_row(text, columns)))
+            .collect();
+    }
+
+    let column = |names: &[&str]| header.iter().position(|name| names.contains(name));
+
+    let (Some(timestamp), Some(value)) =
+        (column(&["timestamp", "measured_at"]), column(&["value"]))
+    else {
+        return vec![(
+            first_line,
+            Err("header has no timestamp or value column".to_owned()),
+        )];
+    };
+
+    let columns = CsvColumns {
+        timestamp,
+        value,
+        facility_id: column(&["facility_id"]),
+        suspect: column(&["suspect"]),
+    };
+
+    lines
+        .map(|(line, text)| (line, csv_row(text, columns)))
+        .collect()
+}
+
+fn csv_row(text: &str, columns: CsvColumns) -> RawRow {
+    let fields = csv_fields(text);
+    let field = |column: usize| {
+        fields
+            .get(column)
+            .copied()
+            .ok_or_else(|| format!("missing column {}", column + 1))
+    };
+
+    let measured_at = timestamp(field(columns.timestamp)?)?;
+    let value = field(columns.value)?;
+    let value = value
+        .parse()
+        .map_err(|_| format!("invalid value {value:?}"))?;
+    let facility_id = columns
+        .facility_id
+        .map(field)
+        .transpose()?
+        .map(str::to_owned);
+    let suspect = match columns.suspect {
+        Some(column) => {
+            let suspect = field(column)?;
+            suspect
+                .parse()
+                .map_err(|_| format!("invalid suspect flag {suspect:?}"))?
+        }
+        None => false,
+    };
+
+    Ok((facility_id, measured_at, value, suspect))
+}
+
+/// Splits a CSV line into its fields, without support for quoted commas
+fn csv_fields(text: &str) -> Vec<&str> {
+    text.split(',')
+        .map(|field| field.trim().trim_matches('"'))
+        .collect()
+}
+
+fn json_row(text: &str) -> RawRow {
+    let row = serde_json::from_str::<JsonRow>(text).map_err(|e| e.to_string())?;
+
+    let measured_at = match row.timestamp {
+        JsonTimestamp::Unix(t) => {
+            DateTime::from_timestamp(t, 0).ok_or_else(|| format!("invalid timestamp {t}"))?
+        }
+        JsonTimestamp::Text(text) => timestamp(&text)?,
+    };
+
+    Ok((row.facility_id, measured_at, row.value, row.suspect))
+}
+
+/// Parses a UNIX timestamp, an RFC 3339 date and time or a date and time in UTC such as `2023-05-01 18:30:00`
+fn timestamp(text: &str) -> Result<DateTime<Utc>, String> {
+    let invalid = || format!("invalid timestamp {text:?}");
+
+    if let Ok(t) = text.parse::<i64>() {
+        return DateTime::from_timestamp(t, 0).ok_or_else(invalid);
+    }
+
+    DateTime::parse_from_rfc3339(text)
+        .map(|t| t.to_utc())
+        .or_else(|_| {
+            NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f").map(|t| t.and_utc())
+        })
+        .map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::config::tests::config, chrono::TimeDelta};
+
+    fn csv(text: &str) -> Result<Vec<ImportRow>, Vec<RowError>> {
+        parse(text, ImportFormat::Csv, &config(), "gym")
+    }
+
+    /// Lines of the rows that failed to parse
+    fn error_lines(result: Result<Vec<ImportRow>, Vec<RowError>>) -> Vec<usize> {
+        result
+            .unwrap_err()
+            .into_iter()
+            .map(|error| error.line)
+            .collect()
+    }
+
+    fn at(t: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(t, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_headerless_csv() {
+        let rows = csv("1700000000,42\n\n1700000060, 43\n").unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                ImportRow {
+                    line: 1,
+                    facility_id: "gym".to_owned(),
+                    measured_at: at(1_700_000_000),
+                    value: 42,
+                    suspect: false,
+                },
+                ImportRow {
+                    line: 3,
+                    facility_id: "gym".to_owned(),
+                    measured_at: at(1_700_000_060),
+                    value: 43,
+                    suspect: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_csv_header_in_any_order() {
+        let rows = csv(
+            "suspect,value,facility_id,measured_at\ntrue,17,pool,1700000000\nfalse,18,gym,1700000000\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            rows,
+            vec![
+                ImportRow {
+                    line: 2,
+                    facility_id: "pool".to_owned(),
+                    measured_at: at(1_700_000_000),
+                    value: 17,
+                    suspect: true,
+                },
+                ImportRow {
+                    line: 3,
+                    facility_id: "gym".to_owned(),
+                    measured_at: at(1_700_000_000),
+                    value: 18,
+                    suspect: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_header_without_required_columns() {
+        assert_eq!(error_lines(csv("time,occupancy\n1700000000,42\n")), vec![1]);
+    }
+
+    #[test]
+    fn parses_timestamp_formats() {
+        let expected = at(1_700_000_000);
+
+        assert_eq!(timestamp("1700000000"), Ok(expected));
+        assert_eq!(timestamp("2023-11-14T22:13:20Z"), Ok(expected));
+        assert_eq!(timestamp("2023-11-14T23:13:20+01:00"), Ok(expected));
+        assert_eq!(timestamp("2023-11-14 22:13:20"), Ok(expected));
+        assert_eq!(timestamp("2023-11-14 22:13:20.000"), Ok(expected));
+        assert!(timestamp("14/11/2023 22:13").is_err());
+    }
+
+    #[test]
+    fn parses_ndjson() {
+        let text = r#"{"timestamp":1700000000,"value":42}
+{"measured_at":"2023-11-14T22:14:20Z","value":43,"facility_id":"pool","suspect":true}"#;
+        let rows = parse(text, ImportFormat::Ndjson, &config(), "gym").unwrap();
+
+        assert_eq!(rows[0].measured_at, at(1_700_000_000));
+        assert_eq!(rows[0].facility_id, "gym");
+        assert_eq!(rows[1].measured_at, at(1_700_000_060));
+        assert_eq!(rows[1].facility_id, "pool");
+        assert!(rows[1].suspect);
+    }
+
+    #[test]
+    fn rejects_values_outside_percentage_range() {
+        assert_eq!(
+            error_lines(csv(
+                "1700000000,-1\n1700000060,256\n1700000120,255\n1700000180,101\n1700000240,100\n1700000300,0\n"
+            )),
+            vec![1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let errors = csv("1700000000,42\n2023-11-14T22:13:20Z,43\n").unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].line, 2);
+        assert_eq!(errors[0].reason, "duplicate of line 1");
+    }
+
+    #[test]
+    fn same_time_of_different_facilities_is_not_duplicate() {
+        assert!(csv("timestamp,value,facility_id\n1700000000,1,gym\n1700000000,2,pool\n").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_facilities_and_future_rows() {
+        let future = (Utc::now() + TimeDelta::days(1)).timestamp();
+
+        assert_eq!(
+            error_lines(csv(&format!(
+                "timestamp,value,facility_id\n1700000000,1,spa\n{future},2,gym\n"
+            ))),
+            vec![2, 3]
+        );
+    }
+}
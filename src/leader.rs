@@ -0,0 +1,76 @@
+//! Leader election using Postgres advisory locks
+//!
+//! Only one instance should fetch the status of a facility at a time, so each fetcher holds a session-level advisory lock on a dedicated connection. If the leader dies its connection is closed, releasing the lock for another instance to take over.
+
+use {
+    sqlx::{Connection, PgConnection, Pool, Postgres},
+    tracing::{info, warn},
+};
+
+/// Prefix of the advisory lock key, to avoid collisions with other users of the database
+const KEY_PREFIX: &str = "isthegymbusy";
+
+pub struct LeaderLock {
+    db: Pool<Postgres>,
+    key: String,
+    /// Dedicated connection, detached from the pool so the lock is never returned to it
+    conn: Option<PgConnection>,
+    leader: bool,
+}
+
+impl LeaderLock {
+    /// Creates a lock on `name` within `namespace`, so that tasks of different kinds never share a lock whatever they are named
+    pub fn new(db: Pool<Postgres>, namespace: &str, name: &str) -> Self {
+        Self {
+            db,
+            key: format!("{KEY_PREFIX}/{namespace}/{name}"),
+            conn: None,
+            leader: false,
+        }
+    }
+
+    /// Attempts to become or remain leader, returning whether this instance is the leader
+    pub async fn poll(&mut self) -> bool {
+        let was_leader = self.leader;
+
+        self.leader = match self.try_lock().await {
+            Ok(leader) => leader,
+            Err(e) => {
+                warn!("Error during leader election for {:?}: {e:?}", self.key);
+                // closing the connection releases the lock if it was still held
+                if let Some(conn) = self.conn.take() {
+                    conn.close_hard().await.ok();
+                }
+                false
+            }
+        };
+
+        match (was_leader, self.leader) {
+            (false, true) => info!("Became leader for {:?}", self.key),
+            (true, false) => warn!("Lost leadership for {:?}", self.key),
+            _ => (),
+        }
+
+        self.leader
+    }
+
+    async fn try_lock(&mut self) -> Result<bool, sqlx::Error> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(self.db.acquire().await?.detach()),
+        };
+
+        if self.leader {
+            // lock is held for as long as the connection is alive
+            conn.ping().await?;
+            return Ok(true);
+        }
+
+        sqlx::query_scalar!(
+            r#"SELECT pg_try_advisory_lock(hashtext($1)) as "acquired!""#,
+            self.key,
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
@@ -1,8 +1,12 @@
 use {
     crate::{
         log::{create_trace_layer, tracing_init},
-        routes::{health, history, index, static_files, status},
-        status::StatusFetcher,
+        routes::{
+            best_time, export_measurements, facilities, forecast, health, history, index,
+            static_files, status, status_stream, ws,
+        },
+        source::HtmlSource,
+        status::{LiveStatus, StatusFetcher, StatusUpdate},
     },
     axum::{routing::get, Router},
     color_eyre::eyre::Result,
@@ -10,21 +14,30 @@ use {
         postgres::{PgPoolOptions, Postgres},
         Pool,
     },
-    std::{
-        net::SocketAddr,
-        sync::{atomic::AtomicU8, Arc},
-        time::Duration,
-    },
-    tokio::{net::TcpListener, task::JoinHandle},
+    std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration},
+    tokio::{net::TcpListener, sync::broadcast, task::JoinHandle},
     tower_http::compression::CompressionLayer,
     tracing::{debug, info},
 };
 
+pub mod anomaly;
+pub mod backtest;
+pub mod calendar;
+pub mod cli;
 pub mod config;
 pub mod error;
+pub mod export;
+pub mod forecast;
+pub mod hours;
+pub mod import;
+pub mod leader;
 pub mod log;
+pub mod notify;
 pub mod routes;
 
+pub mod retry;
+pub mod rollup;
+pub mod source;
 pub mod status;
 
 pub use crate::config::Config;
@@ -38,12 +51,21 @@ const HISTORY_MAX_AGE: Duration = Duration::from_secs(15 * 60);
 /// Static files cached for 15 minutes
 const STATIC_FILES_MAX_AGE: Duration = Duration::from_secs(15 * 60);
 
+/// Number of status updates buffered for slow live update subscribers
+const STATUS_UPDATES_CAPACITY: usize = 64;
+
+/// Interval between keep-alive messages on live update streams
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
 const DATABASE_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(5);
 const DATABASE_MIN_CONNECTIONS: u32 = 5;
 
 #[derive(Clone)]
 pub struct AppState {
-    capacity: Arc<AtomicU8>,
+    /// Current occupancy of each facility, keyed by facility ID
+    statuses: Arc<HashMap<String, Arc<LiveStatus>>>,
+    /// Live updates of all facilities' occupancy
+    updates: broadcast::Sender<StatusUpdate>,
     db: Pool<Postgres>,
     config: Config,
 }
@@ -68,11 +90,33 @@ pub async fn start(config: &Config) -> Result<Handle> {
         .await
         .unwrap();
 
-    debug!("running migrations");
-    sqlx::migrate!().run(&db).await?;
+    migrate(&db, config).await?;
+
+    let (updates, _) = broadcast::channel(STATUS_UPDATES_CAPACITY);
+    let mut statuses = HashMap::new();
+
+    for facility in &config.facilities {
+        let status = StatusFetcher::init(
+            db.clone(),
+            config,
+            facility,
+            Arc::new(HtmlSource::new(&facility.url, &facility.pattern)?),
+            updates.clone(),
+        )
+        .await;
+
+        statuses.insert(facility.id.clone(), status);
+    }
+
+    let statuses = Arc::new(statuses);
 
-    let capacity =
-        StatusFetcher::init(db.clone(), Duration::from_secs(config.fetch_interval)).await;
+    rollup::spawn_rollup_task(
+        db.clone(),
+        config.facilities.iter().map(|f| f.id.clone()).collect(),
+    );
+
+    // keep statuses up to date with measurements made by other instances
+    notify::spawn_listener(db.clone(), statuses.clone());
 
     let compression = CompressionLayer::new().br(true).deflate(true).gzip(true);
 
@@ -80,13 +124,37 @@ pub async fn start(config: &Config) -> Result<Handle> {
     let router = Router::new()
         .route("/health", get(health))
         .route("/", get(index))
+        .route("/history", get(history::history))
         .route("/history/today", get(history::today))
         .route("/history/average", get(history::average))
         .route("/history/year", get(history::year))
+        .route("/history/heatmap", get(history::heatmap))
         .route("/status", get(status))
+        .route("/status/stream", get(status_stream))
+        .route("/best-time", get(best_time))
+        .route("/forecast", get(forecast))
+        .route("/export/measurements", get(export_measurements))
+        .route("/ws", get(ws))
+        .route("/facilities", get(facilities))
+        .route("/facilities/{facility}/history", get(history::history))
+        .route("/facilities/{facility}/history/today", get(history::today))
+        .route(
+            "/facilities/{facility}/history/average",
+            get(history::average),
+        )
+        .route("/facilities/{facility}/history/year", get(history::year))
+        .route(
+            "/facilities/{facility}/history/heatmap",
+            get(history::heatmap),
+        )
+        .route("/facilities/{facility}/best-time", get(best_time))
+        .route("/facilities/{facility}/forecast", get(forecast))
+        .route("/facilities/{facility}/status", get(status))
+        .route("/facilities/{facility}/status/stream", get(status_stream))
         .fallback(static_files)
         .with_state(AppState {
-            capacity,
+            statuses,
+            updates,
             db,
             config: config.clone(),
         })
@@ -116,6 +184,25 @@ pub async fn start(config: &Config) -> Result<Handle> {
     })
 }
 
+/// Runs any pending migrations and registers the configured facilities
+pub async fn migrate(db: &Pool<Postgres>, config: &Config) -> Result<()> {
+    debug!("running migrations");
+    sqlx::migrate!().run(db).await?;
+
+    // measurements must belong to a registered facility
+    for facility in &config.facilities {
+        sqlx::query!(
+            "INSERT INTO facilities (id, name) VALUES ($1, $2) ON CONFLICT (id) DO UPDATE SET name = EXCLUDED.name",
+            facility.id,
+            facility.name,
+        )
+        .execute(db)
+        .await?;
+    }
+
+    Ok(())
+}
+
 /// Handle for running an instance
 pub struct Handle {
     // Socket address instance is bound to
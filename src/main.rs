@@ -1,10 +1,7 @@
-use {
-    color_eyre::eyre::Result,
-    isthegymbusy::{start, Config},
-};
+use {clap::Parser, color_eyre::eyre::Result, isthegymbusy::cli::Cli};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    start(&Config::new()?).await?.join().await
+    Cli::parse().run().await
 }
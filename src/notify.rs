@@ -0,0 +1,92 @@
+//! Propagation of new measurements between instances using Postgres LISTEN/NOTIFY
+
+use {
+    crate::status::{LiveStatus, Reading},
+    chrono::DateTime,
+    serde::{Deserialize, Serialize},
+    sqlx::{postgres::PgListener, Executor, Pool, Postgres},
+    std::{collections::HashMap, sync::Arc, time::Duration},
+    tokio::time::sleep,
+    tracing::{debug, error, warn},
+};
+
+/// Channel on which new measurements are announced
+pub const CHANNEL: &str = "measurements";
+
+/// Delay before reconnecting after the listener fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Payload of a notification on `CHANNEL`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MeasurementNotification {
+    pub facility_id: String,
+    /// UNIX timestamp in microseconds
+    pub measured_at: i64,
+    pub value: u8,
+}
+
+/// Announces a new measurement to all instances, delivered when the surrounding transaction commits
+pub async fn notify<'e, E: Executor<'e, Database = Postgres>>(
+    executor: E,
+    facility_id: &str,
+    reading: &Reading,
+) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(&MeasurementNotification {
+        facility_id: facility_id.to_owned(),
+        measured_at: reading.measured_at.timestamp_micros(),
+        value: reading.value,
+    })
+    .unwrap();
+
+    sqlx::query!("SELECT pg_notify($1, $2)", CHANNEL, payload)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+/// Spawns a task updating the live statuses from notifications sent by any instance
+pub fn spawn_listener(db: Pool<Postgres>, statuses: Arc<HashMap<String, Arc<LiveStatus>>>) {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = listen(&db, &statuses).await {
+                error!("Measurement listener failed: {e:?}");
+            }
+            sleep(RECONNECT_DELAY).await;
+        }
+    });
+}
+
+async fn listen(
+    db: &Pool<Postgres>,
+    statuses: &HashMap<String, Arc<LiveStatus>>,
+) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(db).await?;
+    listener.listen(CHANNEL).await?;
+
+    loop {
+        let notification = listener.recv().await?;
+
+        let MeasurementNotification {
+            facility_id,
+            measured_at,
+            value,
+        } = match serde_json::from_str(notification.payload()) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("Ignoring malformed measurement notification: {e}");
+                continue;
+            }
+        };
+
+        let (Some(status), Some(measured_at)) = (
+            statuses.get(&facility_id),
+            DateTime::from_timestamp_micros(measured_at),
+        ) else {
+            continue;
+        };
+
+        debug!("Received measurement {value} for {facility_id}");
+        status.set(Reading { value, measured_at });
+    }
+}
@@ -0,0 +1,82 @@
+//! Retry policy for transient failures
+
+use {crate::config::Config, std::time::Duration};
+
+/// Longest delay between attempts, however many have been made
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for each subsequent retry
+    pub base_delay: Duration,
+    /// Fraction of each delay that is randomised, between 0 and 1
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            max_attempts: config.retry_max_attempts,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            jitter: config.retry_jitter,
+        }
+    }
+
+    /// Gets the delay to wait before making attempt number `attempt` (starting at 1 for the first retry), at most `MAX_DELAY`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(MAX_DELAY);
+
+        delay.mul_f64(1.0 - self.jitter * rand::random_range(0.0..1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            jitter,
+        }
+    }
+
+    #[test]
+    fn delay_doubles_with_each_attempt() {
+        let policy = policy(0.0);
+
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(3), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn delay_is_capped() {
+        let policy = policy(0.0);
+
+        assert_eq!(policy.delay(20), MAX_DELAY);
+        assert_eq!(policy.delay(u32::MAX), MAX_DELAY);
+    }
+
+    #[test]
+    fn jitter_only_shortens_delay() {
+        let jittered = policy(0.5);
+
+        for attempt in 1..=20 {
+            let nominal = policy(0.0).delay(attempt);
+
+            for _ in 0..100 {
+                let delay = jittered.delay(attempt);
+                assert!(delay <= nominal, "{delay:?} > {nominal:?}");
+                assert!(delay >= nominal / 2, "{delay:?} < {nominal:?} / 2");
+            }
+        }
+    }
+}
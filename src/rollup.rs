@@ -0,0 +1,117 @@
+//! Daily rollups of measurements
+//!
+//! Aggregating a year of raw measurements on every request is too slow, so the statistics of each day are stored in `daily_rollups` and periodically recomputed from a couple of days before the most recent rolled up day, which also picks up measurements stored late.
+//!
+//! Days before that window are never recomputed automatically, so anything else changing their measurements, such as an import, must call `refresh` for the affected days itself. Pruning deletes the measurements of old days but keeps their rollups, which must then be skipped by later refreshes since they can no longer be recomputed from the full day.
+
+use {
+    crate::leader::LeaderLock,
+    chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc},
+    sqlx::{Pool, Postgres},
+    std::time::Duration,
+    tokio::time::interval,
+    tracing::{debug, error},
+};
+
+/// Interval between refreshes of the rollups
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Number of days before the most recent rolled up day that are recomputed on each refresh
+const REFRESH_WINDOW_DAYS: i64 = 2;
+
+/// Spawns a task refreshing the recent rollups of each facility, run by only one instance at a time
+pub fn spawn_rollup_task(db: Pool<Postgres>, facilities: Vec<String>) {
+    tokio::spawn(async move {
+        let mut leader = LeaderLock::new(db.clone(), "rollups", "daily");
+        let mut interval = interval(ROLLUP_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if !leader.poll().await {
+                continue;
+            }
+
+            for facility in &facilities {
+                if let Err(e) = refresh_latest(&db, facility).await {
+                    error!("Failed to refresh rollups for {facility}: {e:?}");
+                }
+            }
+        }
+    });
+}
+
+/// Refreshes the rollups of a facility from a few days before its most recent rolled up day, or from the beginning if there are none
+pub async fn refresh_latest(db: &Pool<Postgres>, facility_id: &str) -> Result<u64, sqlx::Error> {
+    let last_day = sqlx::query_scalar!(
+        "SELECT MAX(day) FROM daily_rollups WHERE facility_id = $1",
+        facility_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    let since = last_day.map_or(DateTime::UNIX_EPOCH, |day| {
+        (day - TimeDelta::days(REFRESH_WINDOW_DAYS))
+            .and_time(NaiveTime::MIN)
+            .and_utc()
+    });
+
+    refresh(db, facility_id, since, &[]).await
+}
+
+/// Recomputes the rollups of all days of a facility with measurements at or after the start of the UTC day containing `since`, except the days in `skip`, returning the number of days updated
+pub async fn refresh(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+    since: DateTime<Utc>,
+    skip: &[NaiveDate],
+) -> Result<u64, sqlx::Error> {
+    let since = since.date_naive().and_time(NaiveTime::MIN).and_utc();
+
+    let days = sqlx::query!(
+        r#"
+            WITH hourly AS (
+                SELECT
+                    date_trunc('hour', measured_at, 'UTC') as hour,
+                    AVG(value) as avg,
+                    SUM(value) as sum,
+                    MIN(value) as min,
+                    MAX(value) as max,
+                    COUNT(*) as count
+                FROM measurements
+                WHERE
+                    facility_id = $1 AND
+                    measured_at >= $2 AND
+                    (measured_at AT TIME ZONE 'UTC')::date <> ALL($3::date[])
+                GROUP BY 1
+            )
+            INSERT INTO daily_rollups (facility_id, day, avg, min, max, peak_hour, count)
+            SELECT
+                $1,
+                (date_trunc('day', hour, 'UTC') AT TIME ZONE 'UTC')::date,
+                (SUM(sum) / SUM(count))::real,
+                MIN(min),
+                MAX(max),
+                extract(hour from (array_agg(hour ORDER BY avg DESC))[1] AT TIME ZONE 'UTC')::smallint,
+                SUM(count)::integer
+            FROM hourly
+            GROUP BY 2
+            ON CONFLICT (facility_id, day) DO UPDATE SET
+                avg = EXCLUDED.avg,
+                min = EXCLUDED.min,
+                max = EXCLUDED.max,
+                peak_hour = EXCLUDED.peak_hour,
+                count = EXCLUDED.count
+        "#,
+        facility_id,
+        since,
+        skip,
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+
+    debug!("Refreshed {days} daily rollup(s) for {facility_id} since {since}");
+
+    Ok(days)
+}
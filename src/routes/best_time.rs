@@ -0,0 +1,197 @@
+//! Recommends the quietest times to visit a facility
+
+use {
+    crate::{
+        error::Error,
+        routes::{
+            history::{average_query, HistoryQuery, DEFAULT_TRIM, DEFAULT_WEEKS},
+            Facility, FacilityHours,
+        },
+        AppState,
+    },
+    axum::{
+        extract::{Query, State},
+        Json,
+    },
+    chrono::{DateTime, Datelike, NaiveDate, NaiveTime, TimeDelta, Utc, Weekday},
+    serde::{Deserialize, Serialize},
+};
+
+/// Default length of a visit in minutes
+const DEFAULT_DURATION: u32 = 60;
+
+/// Default number of recommendations
+const DEFAULT_LIMIT: usize = 3;
+
+/// Largest allowed number of recommendations
+const MAX_LIMIT: usize = 20;
+
+/// Factor by which the difference between the live reading and the typical value decays per hour
+const LIVE_DECAY_PER_HOUR: f64 = 0.5;
+
+#[derive(Debug, Deserialize)]
+pub struct BestTimeParams {
+    /// Day of the week of the visit, defaults to today
+    pub weekday: Option<Weekday>,
+    /// Earliest start of the visit as `HH:MM` in the facility's time zone
+    pub from: Option<String>,
+    /// Latest end of the visit as `HH:MM` in the facility's time zone
+    pub to: Option<String>,
+    /// Length of the visit in minutes
+    pub duration: Option<u32>,
+    /// Maximum number of recommendations
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BestTimes {
+    /// Date of the visit, the next occurrence of the requested day of the week
+    pub date: NaiveDate,
+    /// Length of the visit in minutes
+    pub duration: u32,
+    /// Recommended visits, quietest first
+    pub slots: Vec<BestTime>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BestTime {
+    /// UNIX timestamp of the start of the visit
+    pub start: i64,
+    /// UNIX timestamp of the end of the visit
+    pub end: i64,
+    /// Expected average occupancy percentage during the visit
+    pub expected: f64,
+}
+
+/// Ranks the quietest times to visit on a day during its opening hours based on its typical busyness, adjusted by the live reading if the day is today
+pub async fn best_time(
+    State(AppState { db, statuses, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    Query(params): Query<BestTimeParams>,
+) -> Result<Json<BestTimes>, Error> {
+    let now = Utc::now();
+    let today = hours.date(now);
+    let weekday = params.weekday.unwrap_or(today.weekday());
+
+    let window_start = parse_time(params.from.as_deref())?.unwrap_or(NaiveTime::MIN);
+    let window_end = parse_time(params.to.as_deref())?;
+    let duration = params.duration.unwrap_or(DEFAULT_DURATION);
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    if duration == 0 {
+        return Err(Error::InvalidParameters(
+            "duration must be at least 1 minute".to_owned(),
+        ));
+    }
+
+    let date = today + TimeDelta::days(days_until(today, weekday).into());
+
+    // only averages days of the same calendar period kind as the day being planned
+    let query = HistoryQuery {
+        same_period_as: Some(date),
+        ..average_query(weekday, DEFAULT_WEEKS, DEFAULT_TRIM, &hours)
+    };
+    let history = query.fetch(&db, &facility).await?;
+
+    // typical values are timestamped on the most recent occurrence of the day, so move them to the next
+    let shift = hours.midnight(date) - hours.midnight(hours.date(query.from));
+    let interval = TimeDelta::from_std(history.interval).unwrap();
+
+    let mut slots = history
+        .values
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, value)| (query.from + shift + interval * i as i32, *value))
+        .collect::<Vec<_>>();
+
+    // for today, shift the upcoming typical values towards the live reading, unless it is from before the facility closed
+    if date == today && hours.is_open(now) {
+        let status = statuses[&facility].snapshot();
+        if let (Some(live), false) = (status.value, status.stale) {
+            adjust_to_live(&mut slots, now, interval, live.into());
+        }
+    }
+
+    let slots_per_visit = (u64::from(duration) * 60).div_ceil(history.interval.as_secs()) as usize;
+    let visit = TimeDelta::minutes(duration.into());
+
+    let mut candidates = slots
+        .windows(slots_per_visit)
+        .filter_map(|window| {
+            let start = window[0].0;
+            let end = start + visit;
+            let local = |t: DateTime<Utc>| t.with_timezone(&hours.timezone).naive_local();
+
+            // visits in the slot that has already started would begin in the past
+            if start < now
+                || local(start).time() < window_start
+                || window_end.is_some_and(|window_end| local(end).time() > window_end)
+                || local(end).date() != local(start).date()
+                || !hours.is_open_throughout(start, end)
+            {
+                return None;
+            }
+
+            let values = window.iter().filter_map(|(_, v)| *v).collect::<Vec<_>>();
+            if values.is_empty() {
+                return None;
+            }
+
+            Some(BestTime {
+                start: start.timestamp(),
+                end: end.timestamp(),
+                expected: values.iter().sum::<f64>() / values.len() as f64,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    candidates.sort_by(|a, b| a.expected.total_cmp(&b.expected));
+    candidates.truncate(limit);
+
+    Ok(Json(BestTimes {
+        date,
+        duration,
+        slots: candidates,
+    }))
+}
+
+/// Adds the difference between the live reading and the typical value of the current slot to upcoming slots, decaying with time
+fn adjust_to_live(
+    slots: &mut [(DateTime<Utc>, Option<f64>)],
+    now: DateTime<Utc>,
+    interval: TimeDelta,
+    live: f64,
+) {
+    let Some(typical) = slots
+        .iter()
+        .find(|(start, _)| *start <= now && now < *start + interval)
+        .and_then(|(_, value)| *value)
+    else {
+        return;
+    };
+
+    let offset = live - typical;
+
+    for (start, value) in slots
+        .iter_mut()
+        .filter(|(start, _)| *start + interval > now)
+    {
+        let hours = (*start - now).num_seconds().max(0) as f64 / 3600.0;
+        *value = value.map(|v| (v + offset * LIVE_DECAY_PER_HOUR.powf(hours)).max(0.0));
+    }
+}
+
+fn parse_time(time: Option<&str>) -> Result<Option<NaiveTime>, Error> {
+    time.map(|time| {
+        NaiveTime::parse_from_str(time, "%H:%M")
+            .map_err(|_| Error::InvalidParameters(format!("invalid time {time:?}, expected HH:MM")))
+    })
+    .transpose()
+}
+
+/// Gets the number of days until the next `weekday` on or after `date`
+fn days_until(date: NaiveDate, weekday: Weekday) -> u32 {
+    (7 + weekday.num_days_from_monday() - date.weekday().num_days_from_monday()) % 7
+}
@@ -0,0 +1,137 @@
+//! Bulk export of raw measurements
+
+use {
+    crate::{
+        error::Error,
+        export::{self, ExportFormat},
+        AppState,
+    },
+    axum::{
+        body::{Body, Bytes},
+        extract::{Query, State},
+        http::{header::CONTENT_DISPOSITION, HeaderValue},
+        response::{IntoResponse, Response},
+    },
+    axum_extra::{
+        headers::{authorization::Bearer, Authorization, CacheControl, ContentType},
+        TypedHeader,
+    },
+    chrono::{DateTime, Utc},
+    serde::Deserialize,
+    std::mem::take,
+    tokio::sync::mpsc,
+    tokio_stream::{wrappers::ReceiverStream, StreamExt},
+    tracing::error,
+};
+
+/// Size in bytes above which encoded rows are sent as a chunk
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of encoded chunks buffered ahead of a slow client before reading further rows
+const BUFFERED_CHUNKS: usize = 4;
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    /// Facility to export, defaults to all facilities
+    pub facility: Option<String>,
+    /// UNIX timestamp of the earliest measurement, inclusive, defaults to the first
+    pub from: Option<i64>,
+    /// UNIX timestamp of the latest measurement, exclusive, defaults to now
+    pub to: Option<i64>,
+    /// Defaults to `csv`
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+/// Streams the measurements in a time range, ordered by facility and time, as CSV or newline delimited JSON
+///
+/// Requires the configured export token as a bearer token. Rows are read from the database only as fast as the client receives them, so exports of any size use constant memory.
+pub async fn export_measurements(
+    State(AppState { db, config, .. }): State<AppState>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, Error> {
+    let authorized = match (&config.export_token, authorization) {
+        (Some(token), Some(TypedHeader(Authorization(bearer)))) => {
+            constant_time_eq(token.as_bytes(), bearer.token().as_bytes())
+        }
+        _ => false,
+    };
+    if !authorized {
+        return Err(Error::Unauthorized);
+    }
+
+    if let Some(facility) = &params.facility {
+        if config.facility(facility).is_none() {
+            return Err(Error::UnknownFacility(facility.clone()));
+        }
+    }
+
+    let timestamp = |t: i64| {
+        DateTime::from_timestamp(t, 0)
+            .ok_or_else(|| Error::InvalidParameters(format!("invalid timestamp {t}")))
+    };
+    let from = params
+        .from
+        .map(timestamp)
+        .transpose()?
+        .unwrap_or(DateTime::UNIX_EPOCH);
+    let to = params
+        .to
+        .map(timestamp)
+        .transpose()?
+        .unwrap_or_else(Utc::now);
+
+    let format = params.format;
+    let (chunks, rx) = mpsc::channel::<Result<Bytes, sqlx::Error>>(BUFFERED_CHUNKS);
+
+    tokio::spawn(async move {
+        let mut rows = export::measurements(&db, params.facility.as_deref(), from, to);
+
+        let mut buf = format.header();
+
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(row) => format.encode(&row, &mut buf),
+                Err(e) => {
+                    error!("Failed to export measurements: {e:?}");
+                    // aborts the response so that the client does not mistake it for a complete export
+                    let _ = chunks.send(Err(e)).await;
+                    return;
+                }
+            }
+
+            // only fails if the client has disconnected
+            if buf.len() >= CHUNK_SIZE && chunks.send(Ok(take(&mut buf).into())).await.is_err() {
+                return;
+            }
+        }
+
+        if !buf.is_empty() {
+            let _ = chunks.send(Ok(buf.into())).await;
+        }
+    });
+
+    let disposition = format!(
+        "attachment; filename=\"measurements.{}\"",
+        format.extension()
+    );
+
+    Ok((
+        TypedHeader(ContentType::from(
+            format.content_type().parse::<mime_guess::Mime>().unwrap(),
+        )),
+        TypedHeader(CacheControl::new().with_no_store()),
+        [(
+            CONTENT_DISPOSITION,
+            HeaderValue::from_str(&disposition).unwrap(),
+        )],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response())
+}
+
+/// Compares secrets in time independent of where they differ
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
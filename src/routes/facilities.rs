@@ -0,0 +1,70 @@
+use {
+    crate::{error::Error, hours::OpeningHours, AppState},
+    axum::{
+        extract::{FromRequestParts, Path, State},
+        http::request::Parts,
+        response::{IntoResponse, Response},
+        Json, RequestPartsExt,
+    },
+    serde::Serialize,
+};
+
+/// Facility a request is scoped to, taken from the `{facility}` path segment or the default facility if absent
+pub struct Facility(pub String);
+
+impl FromRequestParts<AppState> for Facility {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let id = match parts
+            .extract::<Option<Path<String>>>()
+            .await
+            .map_err(IntoResponse::into_response)?
+        {
+            Some(Path(id)) => id,
+            None => state.config.default_facility().id.clone(),
+        };
+
+        if !state.statuses.contains_key(&id) {
+            return Err(Error::UnknownFacility(id).into_response());
+        }
+
+        Ok(Self(id))
+    }
+}
+
+/// Opening hours of the facility a request is scoped to, resolved in the same way as `Facility`
+pub struct FacilityHours(pub OpeningHours);
+
+impl FromRequestParts<AppState> for FacilityHours {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Response> {
+        let Facility(id) = Facility::from_request_parts(parts, state).await?;
+
+        match state.config.facility(&id) {
+            Some(facility) => Ok(Self(facility.hours.clone())),
+            None => Err(Error::UnknownFacility(id).into_response()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct FacilityEntry {
+    pub id: String,
+    pub name: String,
+}
+
+/// Lists configured facilities
+pub async fn facilities(State(AppState { config, .. }): State<AppState>) -> impl IntoResponse {
+    Json(
+        config
+            .facilities
+            .iter()
+            .map(|facility| FacilityEntry {
+                id: facility.id.clone(),
+                name: facility.name.clone(),
+            })
+            .collect::<Vec<_>>(),
+    )
+}
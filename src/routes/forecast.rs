@@ -0,0 +1,63 @@
+//! Short-term forecast of a facility's occupancy
+
+use {
+    crate::{
+        error::Error,
+        forecast::{forecast as predict, DEFAULT_HORIZON},
+        routes::{
+            history::{Aggregate, History},
+            Facility, FacilityHours, Format,
+        },
+        AppState,
+    },
+    axum::{
+        extract::{Query, State},
+        response::Response,
+    },
+    chrono::{TimeDelta, Utc},
+    serde::Deserialize,
+    std::time::Duration,
+};
+
+/// Largest allowed forecast horizon in hours
+const MAX_HOURS: u64 = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct ForecastParams {
+    /// Number of hours to forecast, defaults to 3
+    pub hours: Option<u64>,
+}
+
+/// Forecasts the occupancy of the next few hours, in the same format as the history routes
+pub async fn forecast(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+    Query(params): Query<ForecastParams>,
+) -> Result<Response, Error> {
+    let horizon = match params.hours {
+        None => DEFAULT_HORIZON,
+        Some(hours @ 1..=MAX_HOURS) => Duration::from_secs(hours * 60 * 60),
+        Some(_) => {
+            return Err(Error::InvalidParameters(format!(
+                "hours must be between 1 and {MAX_HOURS}"
+            )))
+        }
+    };
+
+    let forecast = predict(&db, &facility, Utc::now(), horizon).await?;
+
+    let slots = forecast.values.len();
+    let mut history = History {
+        latest: forecast.first
+            + TimeDelta::from_std(forecast.interval).unwrap() * slots.saturating_sub(1) as i32,
+        interval: forecast.interval,
+        aggregate: Aggregate::Avg,
+        values: forecast.values.into_iter().rev().collect(),
+        closed: vec![false; slots],
+    };
+    history.mark_closed(&hours);
+
+    Ok(history.into_response_as(format))
+}
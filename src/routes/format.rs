@@ -0,0 +1,163 @@
+//! Content negotiation between the binary and JSON representations of responses
+
+use {
+    crate::error::Error,
+    axum::{
+        extract::{FromRequestParts, Query},
+        http::{
+            header::{ACCEPT, VARY},
+            request::Parts,
+            HeaderName, HeaderValue,
+        },
+        response::{IntoResponse, Response},
+    },
+    mime_guess::mime::{APPLICATION_JSON, APPLICATION_OCTET_STREAM},
+    serde::Deserialize,
+};
+
+/// `Vary` header of negotiated responses, so that caches keep each representation separately
+pub const VARY_ACCEPT: [(HeaderName, HeaderValue); 1] =
+    [(VARY, HeaderValue::from_static("accept"))];
+
+/// Representation of a response, taken from the `format` query parameter or otherwise the `Accept` header, defaulting to binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Binary,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+struct FormatParams {
+    format: Option<Format>,
+}
+
+impl Format {
+    /// Chooses the format with the highest quality in an `Accept` header, preferring binary on ties
+    fn from_accept(accept: &str) -> Self {
+        let quality = |format: &str| {
+            accept
+                .split(',')
+                .filter_map(|range| {
+                    let mut params = range.split(';').map(str::trim);
+                    let media_type = params.next()?;
+                    let q = params
+                        .find_map(|param| param.strip_prefix("q="))
+                        .map_or(Some(1.0), |q| q.parse::<f32>().ok())?;
+
+                    media_type.eq_ignore_ascii_case(format).then_some(q)
+                })
+                .fold(0.0, f32::max)
+        };
+
+        if quality(APPLICATION_JSON.as_ref()) > quality(APPLICATION_OCTET_STREAM.as_ref()) {
+            Self::Json
+        } else {
+            Self::Binary
+        }
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Response> {
+        let Query(FormatParams { format }) = Query::try_from_uri(&parts.uri).map_err(|_| {
+            Error::InvalidParameters("format must be binary or json".to_owned()).into_response()
+        })?;
+
+        Ok(format.unwrap_or_else(|| {
+            parts
+                .headers
+                .get(ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .map_or_else(Self::default, Self::from_accept)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, axum::http::Request};
+
+    #[test]
+    fn json_is_chosen_when_accepted() {
+        assert_eq!(Format::from_accept("application/json"), Format::Json);
+        assert_eq!(Format::from_accept("Application/JSON"), Format::Json);
+        assert_eq!(
+            Format::from_accept("application/octet-stream;q=0.5, application/json"),
+            Format::Json
+        );
+    }
+
+    #[test]
+    fn wildcard_is_binary() {
+        assert_eq!(Format::from_accept("*/*"), Format::Binary);
+    }
+
+    #[test]
+    fn json_with_zero_quality_is_binary() {
+        assert_eq!(Format::from_accept("application/json;q=0"), Format::Binary);
+    }
+
+    #[test]
+    fn higher_quality_wins() {
+        assert_eq!(
+            Format::from_accept("application/octet-stream, application/json;q=0.9"),
+            Format::Binary
+        );
+    }
+
+    #[test]
+    fn ties_are_binary() {
+        assert_eq!(
+            Format::from_accept("application/json, application/octet-stream"),
+            Format::Binary
+        );
+    }
+
+    #[test]
+    fn malformed_quality_is_ignored() {
+        assert_eq!(
+            Format::from_accept("application/json;q=high"),
+            Format::Binary
+        );
+        assert_eq!(
+            Format::from_accept("application/json;q=high, application/json;q=0.1"),
+            Format::Json
+        );
+    }
+
+    async fn extract(uri: &str, accept: Option<&str>) -> Result<Format, Response> {
+        let mut request = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            request = request.header(ACCEPT, accept);
+        }
+        let (mut parts, ()) = request.body(()).unwrap().into_parts();
+
+        Format::from_request_parts(&mut parts, &()).await
+    }
+
+    #[tokio::test]
+    async fn query_parameter_overrides_accept() {
+        assert_eq!(
+            extract("/status?format=binary", Some("application/json"))
+                .await
+                .unwrap(),
+            Format::Binary
+        );
+        assert_eq!(
+            extract("/status?format=json", None).await.unwrap(),
+            Format::Json
+        );
+        assert_eq!(extract("/status", None).await.unwrap(), Format::Binary);
+    }
+
+    #[tokio::test]
+    async fn invalid_query_parameter_is_rejected() {
+        let response = extract("/status?format=xml", None).await.unwrap_err();
+
+        assert_eq!(response.status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+}
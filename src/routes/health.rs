@@ -1,13 +1,40 @@
 use {
-    crate::AppState,
-    axum::{extract::State, http::StatusCode, response::IntoResponse},
+    crate::{status::StatusSnapshot, AppState},
+    axum::{extract::State, http::StatusCode, response::IntoResponse, Json},
+    serde::Serialize,
+    std::collections::BTreeMap,
 };
 
+#[derive(Serialize)]
+pub struct HealthReport {
+    /// Whether the database is reachable
+    pub database: bool,
+    /// Current status of each facility, keyed by facility ID
+    pub facilities: BTreeMap<String, StatusSnapshot>,
+}
+
 /// Tests node health
-pub async fn health(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
-    if sqlx::query("SELECT 1").fetch_one(&db).await.is_ok() {
+///
+/// Stale facility statuses are reported but do not fail the check, as they are usually caused by the upstream rather than this node.
+pub async fn health(State(AppState { db, statuses, .. }): State<AppState>) -> impl IntoResponse {
+    let database = sqlx::query("SELECT 1").fetch_one(&db).await.is_ok();
+
+    let status_code = if database {
         StatusCode::OK
     } else {
         StatusCode::SERVICE_UNAVAILABLE
-    }
+    };
+
+    let facilities = statuses
+        .iter()
+        .map(|(id, status)| (id.clone(), status.snapshot()))
+        .collect();
+
+    (
+        status_code,
+        Json(HealthReport {
+            database,
+            facilities,
+        }),
+    )
 }
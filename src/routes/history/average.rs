@@ -1,128 +1,92 @@
-//! Gets the historical average busyness for this day
+//! Gets the historical average busyness for a day of the week
 
 use {
-    crate::{AppState, HISTORY_MAX_AGE},
-    axum::{
-        extract::State,
-        http::{HeaderName, HeaderValue},
-        response::IntoResponse,
+    crate::{
+        error::Error,
+        hours::OpeningHours,
+        routes::{
+            history::query::{Aggregate, HistoryQuery, WEEK},
+            Facility, FacilityHours, Format,
+        },
+        AppState,
     },
-    axum_extra::{
-        headers::{self, CacheControl, ContentType, Header},
-        TypedHeader,
+    axum::{
+        extract::{Query, State},
+        response::Response,
     },
-    chrono::{DateTime, Utc},
-    mime_guess::mime::APPLICATION_OCTET_STREAM,
-    std::{iter::once, time::Duration},
+    chrono::{Datelike, NaiveDate, TimeDelta, Utc, Weekday},
+    serde::Deserialize,
+    std::time::Duration,
 };
 
 /// Size of time intervals in which to group and average measurements in
 const INTERVAL: Duration = Duration::from_secs(15 * 60);
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Entry {
-    pub value: u8,
-    pub timestamp: i64,
+/// Default number of weeks to average over
+pub const DEFAULT_WEEKS: u32 = 4;
+
+/// Default fraction of the lowest and highest measurements in each interval to ignore
+pub const DEFAULT_TRIM: f64 = 0.1;
+
+#[derive(Debug, Deserialize)]
+pub struct AverageParams {
+    /// Day of the week to average, such as `mon` or `monday`, defaults to today's
+    pub weekday: Option<Weekday>,
+    /// Number of past weeks to average over
+    pub weeks: Option<u32>,
+    /// Fraction of the lowest and highest measurements in each interval to ignore as outliers
+    pub trim: Option<f64>,
+    /// Whether to only average days of the same calendar period kind as today, such as term or vacation, defaults to true
+    pub same_period: Option<bool>,
 }
 
-pub async fn average(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
-    struct DbEntry {
-        measured_at: DateTime<Utc>,
-        value: i16,
+/// Gets the typical busyness of each interval on a day of the week, timestamped on the most recent occurrence of that day (today if it is the same day)
+pub async fn average(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+    Query(params): Query<AverageParams>,
+) -> Result<Response, Error> {
+    let mut query = query(
+        params
+            .weekday
+            .unwrap_or_else(|| hours.date(Utc::now()).weekday()),
+        params.weeks.unwrap_or(DEFAULT_WEEKS),
+        params.trim.unwrap_or(DEFAULT_TRIM),
+        &hours,
+    );
+    if !params.same_period.unwrap_or(true) {
+        query.same_period_as = None;
     }
+    query.validate()?;
 
-    // get average for entries in the past few days
-    let history = sqlx::query_as!(
-        DbEntry,
-        r#"
-        SELECT
-            date_trunc('day', NOW()) + interval '15 minutes' * intervals.int_start  as "measured_at!",
-            CASE
-                WHEN COUNT(measurements.value) > 0 THEN AVG(measurements.value)::smallint
-                ELSE 255::smallint
-            END as "value!"
-        FROM (
-            SELECT
-                generate_series(
-                    6 * 4,
-                    22 * 4
-                ) as int_start
-        ) as intervals
-        LEFT JOIN measurements ON (
-            measurements.measured_at > NOW() - interval '7 days' AND
-            measurements.measured_at >= date_trunc('day', measurements.measured_at) + (interval '15 minutes' * intervals.int_start) AND
-            measurements.measured_at < date_trunc('day', measurements.measured_at) + (interval '15 minutes' * intervals.int_start) + interval '15 minutes' AND
-            measurements.value > 0
-        )
-        GROUP BY intervals.int_start
-        ORDER BY intervals.int_start DESC
-        "#,
-        // PgInterval::try_from(INTERVAL).unwrap()
-    )
-    .fetch_all(&db)
-    .await
-    .unwrap();
-
-    let latest_timestamp = history.first().unwrap().measured_at;
-
-    let body = history
-        .into_iter()
-        .map(|DbEntry { value, .. }| value.try_into().unwrap())
-        .collect::<Vec<u8>>();
-
-    (
-        TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(HISTORY_MAX_AGE)
-                .with_public(),
-        ),
-        TypedHeader(HistoryLatest(latest_timestamp)),
-        TypedHeader(HistoryInterval(INTERVAL)),
-        body,
-    )
+    let mut history = query.fetch(&db, &facility).await?;
+    history.mark_closed(&hours.regular());
+    Ok(history.into_response_as(format))
 }
 
-struct HistoryLatest(DateTime<Utc>);
-
-impl Header for HistoryLatest {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-latest");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.timestamp().to_string()).unwrap();
-        values.extend(once(value));
+/// Gets the trimmed average of each interval during the regular opening hours of `weekday` over the past `weeks` weeks, ignoring measurements of 0%, suspect measurements and those made on days of a different calendar period kind to today
+pub fn query(weekday: Weekday, weeks: u32, trim: f64, hours: &OpeningHours) -> HistoryQuery {
+    let today = hours.date(Utc::now());
+    let date = today - TimeDelta::days(days_since(today, weekday).into());
+    let (opens, closes) = hours.window(date, hours.weekday(weekday));
+
+    HistoryQuery {
+        from: opens,
+        to: opens.max(closes - TimeDelta::from_std(INTERVAL).unwrap()),
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        periods: weeks,
+        period: WEEK,
+        trim,
+        exclude_zero: true,
+        exclude_suspect: true,
+        same_period_as: Some(today),
     }
 }
 
-struct HistoryInterval(Duration);
-
-impl Header for HistoryInterval {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-interval");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.as_secs().to_string()).unwrap();
-        values.extend(std::iter::once(value));
-    }
+/// Gets the number of days since the most recent `weekday` on or before `date`
+fn days_since(date: NaiveDate, weekday: Weekday) -> u32 {
+    (7 + date.weekday().num_days_from_monday() - weekday.num_days_from_monday()) % 7
 }
@@ -0,0 +1,162 @@
+//! Gets the typical busyness for each day of the week and time of day
+
+use {
+    crate::{
+        error::Error,
+        hours::WEEKDAYS,
+        routes::{
+            history::{
+                average::{DEFAULT_TRIM, DEFAULT_WEEKS},
+                query::{HistoryInterval, HistoryQuery},
+            },
+            Facility, FacilityHours, Format, VARY_ACCEPT,
+        },
+        AppState, HISTORY_MAX_AGE,
+    },
+    axum::{
+        extract::{Query, State},
+        http::{HeaderName, HeaderValue},
+        response::{IntoResponse, Response},
+        Json,
+    },
+    axum_extra::{
+        headers::{self, CacheControl, ContentType, Header},
+        TypedHeader,
+    },
+    chrono::{TimeDelta, Timelike, Weekday},
+    mime_guess::mime::APPLICATION_OCTET_STREAM,
+    serde::{Deserialize, Serialize},
+    std::{iter::once, time::Duration},
+};
+
+/// Default size of each time of day slot
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Deserialize)]
+pub struct HeatmapParams {
+    /// Number of seconds in each slot
+    pub interval: Option<u64>,
+    /// Number of past weeks to average over
+    pub weeks: Option<u32>,
+    /// Fraction of the lowest and highest measurements in each slot to ignore as outliers
+    pub trim: Option<f64>,
+    /// Whether to only average days of the same calendar period kind as today, defaults to true
+    pub same_period: Option<bool>,
+}
+
+/// Typical busyness by day of the week and time of day
+#[derive(Debug, Serialize)]
+pub struct Heatmap {
+    /// Number of seconds after midnight UTC at which the first slot starts
+    pub start: u32,
+    /// Number of seconds in each slot
+    pub interval: u64,
+    /// Day of the week of each row
+    pub weekdays: [Weekday; 7],
+    /// Rows of slot values in ascending time order, or null if there is no data or the facility is closed
+    pub values: Vec<Vec<Option<u8>>>,
+}
+
+/// Gets a 7 row matrix of the typical busyness of each slot of each day of the week, Monday first
+///
+/// Rows span the earliest opening to the latest closing time of the week. The format is negotiated as in `Format`. The binary format is the rows concatenated, with 254 for slots outside the regular opening hours of the day and 255 for slots without data. The `Heatmap-Start` header contains the number of seconds after midnight UTC of the first slot, and `History-Interval` the number of seconds in each slot.
+pub async fn heatmap(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+    Query(params): Query<HeatmapParams>,
+) -> Result<Response, Error> {
+    let hours = hours.regular();
+    let mut values = Vec::with_capacity(WEEKDAYS.len());
+    let mut bytes = vec![];
+    let mut start = 0;
+    let interval = params
+        .interval
+        .map_or(DEFAULT_INTERVAL, Duration::from_secs);
+
+    for weekday in WEEKDAYS {
+        let average = super::average::query(
+            weekday,
+            params.weeks.unwrap_or(DEFAULT_WEEKS),
+            params.trim.unwrap_or(DEFAULT_TRIM),
+            &hours,
+        );
+
+        // rows all cover the same times of day, regardless of each day's hours
+        let (opens, closes) = hours.span(hours.date(average.from));
+        let query = HistoryQuery {
+            from: opens,
+            to: closes
+                .checked_sub_signed(TimeDelta::from_std(interval).unwrap_or(TimeDelta::MAX))
+                .map_or(opens, |to| to.max(opens)),
+            interval,
+            same_period_as: average
+                .same_period_as
+                .filter(|_| params.same_period.unwrap_or(true)),
+            ..average
+        };
+        query.validate()?;
+
+        let mut history = query.fetch(&db, &facility).await?;
+        history.mark_closed(&hours);
+
+        start = query.from.num_seconds_from_midnight();
+        values.push(
+            history
+                .percentages()
+                .zip(&history.closed)
+                .map(|(value, closed)| value.filter(|_| !closed))
+                .rev()
+                .collect::<Vec<_>>(),
+        );
+        bytes.extend(history.bytes().rev());
+    }
+
+    let heatmap = Heatmap {
+        start,
+        interval: interval.as_secs(),
+        weekdays: WEEKDAYS,
+        values,
+    };
+
+    let cache_control = TypedHeader(
+        CacheControl::new()
+            .with_max_age(HISTORY_MAX_AGE)
+            .with_public(),
+    );
+
+    Ok(match format {
+        Format::Json => (VARY_ACCEPT, cache_control, Json(heatmap)).into_response(),
+        Format::Binary => (
+            VARY_ACCEPT,
+            TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
+            cache_control,
+            TypedHeader(HeatmapStart(heatmap.start)),
+            TypedHeader(HistoryInterval(interval)),
+            bytes,
+        )
+            .into_response(),
+    })
+}
+
+struct HeatmapStart(u32);
+
+impl Header for HeatmapStart {
+    fn name() -> &'static HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("heatmap-start");
+        &NAME
+    }
+
+    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        Err(headers::Error::invalid())
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(once(HeaderValue::from(self.0)));
+    }
+}
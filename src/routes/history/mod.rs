@@ -1,5 +1,18 @@
 mod average;
+mod heatmap;
+mod query;
 mod today;
 mod year;
 
-pub use {average::average, today::today, year::year};
+pub use {
+    average::average,
+    heatmap::heatmap,
+    query::{history, Aggregate, History, HistoryParams, HistoryQuery, DAY, NO_DATA, WEEK},
+    today::today,
+    year::year,
+};
+
+pub(crate) use {
+    average::{query as average_query, DEFAULT_TRIM, DEFAULT_WEEKS},
+    today::query as today_query,
+};
@@ -0,0 +1,622 @@
+//! Parameterised history queries, shared by all history routes
+
+use {
+    crate::{
+        error::Error,
+        hours::OpeningHours,
+        routes::{Facility, FacilityHours, Format, VARY_ACCEPT},
+        AppState, HISTORY_MAX_AGE,
+    },
+    axum::{
+        extract::{Query, State},
+        http::{HeaderName, HeaderValue},
+        response::{IntoResponse, Response},
+        Json,
+    },
+    axum_extra::{
+        headers::{self, CacheControl, ContentType, Header},
+        TypedHeader,
+    },
+    chrono::{DateTime, NaiveDate, TimeDelta, Utc},
+    mime_guess::mime::APPLICATION_OCTET_STREAM,
+    serde::{Deserialize, Serialize},
+    sqlx::{postgres::types::PgInterval, Pool, Postgres},
+    std::{iter::once, time::Duration},
+};
+
+/// Smallest allowed interval
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest allowed interval
+const MAX_INTERVAL: Duration = Duration::from_secs(366 * 24 * 60 * 60);
+
+/// Largest allowed number of intervals multiplied by number of periods
+const MAX_BUCKETS: i64 = 10_000;
+
+/// Largest allowed fraction of values trimmed from each end
+const MAX_TRIM: f64 = 0.45;
+
+/// Latest allowed start of an interval, the end of year 9999, well within the range of Postgres timestamps
+const MAX_TIMESTAMP: i64 = 253_402_300_799;
+
+/// One day
+pub const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One week
+pub const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Value of an interval without any measurements in the binary format
+pub const NO_DATA: u8 = u8::MAX;
+
+/// Value of an interval outside opening hours in the binary format
+pub const CLOSED: u8 = u8::MAX - 1;
+
+/// Function used to combine the measurements in each interval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    #[default]
+    Avg,
+    Min,
+    Max,
+    P50,
+    P90,
+    Count,
+}
+
+impl Aggregate {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Avg => "avg",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::P50 => "p50",
+            Self::P90 => "p90",
+            Self::Count => "count",
+        }
+    }
+}
+
+/// Query parameters of the `/history` route
+#[derive(Debug, Deserialize)]
+pub struct HistoryParams {
+    /// UNIX timestamp of the start of the first interval, defaults to one day before `to`
+    pub from: Option<i64>,
+    /// UNIX timestamp of the start of the last interval, defaults to now
+    pub to: Option<i64>,
+    /// Number of seconds in each interval, defaults to 5 minutes
+    pub interval: Option<u64>,
+    /// Defaults to `avg`
+    #[serde(default)]
+    pub aggregate: Aggregate,
+    /// Number of consecutive days, ending with that of `from`..`to`, whose measurements at the same time of day are combined, defaults to 1
+    pub days: Option<u32>,
+    /// Number of consecutive weeks, ending with that of `from`..`to`, whose measurements at the same time of the week are combined, cannot be used with `days`
+    pub weeks: Option<u32>,
+    /// Fraction of the lowest and highest measurements in each interval to ignore as outliers, defaults to 0
+    pub trim: Option<f64>,
+}
+
+/// Query over the measurements of a facility, grouped into intervals
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryQuery {
+    /// Start of the first interval
+    pub from: DateTime<Utc>,
+    /// Start of the last interval, inclusive
+    pub to: DateTime<Utc>,
+    pub interval: Duration,
+    pub aggregate: Aggregate,
+    /// Number of periods over which each interval is repeated
+    pub periods: u32,
+    /// Time between repetitions of each interval, usually a day or week
+    pub period: Duration,
+    /// Fraction of the lowest and highest measurements in each interval to ignore
+    pub trim: f64,
+    /// Whether measurements of 0% are ignored
+    pub exclude_zero: bool,
+    /// Whether measurements marked as suspect are ignored
+    pub exclude_suspect: bool,
+    /// Day whose calendar period kind, such as term or vacation, all combined measurements must share, or `None` to combine measurements from any day
+    pub same_period_as: Option<NaiveDate>,
+}
+
+/// Single interval of a `History` in the JSON format
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HistoryEntry {
+    /// UNIX timestamp of the start of the interval
+    pub timestamp: i64,
+    /// Aggregated value, or null if the interval contains no measurements or is closed
+    pub value: Option<f64>,
+    /// Whether the interval is entirely outside opening hours
+    pub closed: bool,
+}
+
+/// Result of a `HistoryQuery`, most recent interval first
+#[derive(Debug, Clone)]
+pub struct History {
+    /// Start of the most recent interval
+    pub latest: DateTime<Utc>,
+    pub interval: Duration,
+    pub aggregate: Aggregate,
+    /// Aggregated value of each interval, or `None` if it contains no measurements
+    pub values: Vec<Option<f64>>,
+    /// Whether each interval is entirely outside opening hours
+    pub closed: Vec<bool>,
+}
+
+impl HistoryQuery {
+    /// Checks the query is well formed and within limits
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |msg: &str| Err(Error::InvalidHistoryQuery(msg.to_owned()));
+
+        if !(MIN_INTERVAL..=MAX_INTERVAL).contains(&self.interval) {
+            return invalid("interval must be between 60 seconds and 366 days");
+        }
+
+        if self.from < DateTime::UNIX_EPOCH || self.to.timestamp() > MAX_TIMESTAMP {
+            return invalid("from and to must be between 1970 and 9999");
+        }
+
+        if self.to < self.from {
+            return invalid("to must not be before from");
+        }
+
+        if self.periods == 0 {
+            return invalid("days and weeks must be at least 1");
+        }
+
+        if !(0.0..=MAX_TRIM).contains(&self.trim) {
+            return invalid("trim must be between 0 and 0.45");
+        }
+
+        let buckets = (self.to - self.from).num_seconds() / self.interval.as_secs() as i64 + 1;
+        if buckets.saturating_mul(self.periods.into()) > MAX_BUCKETS {
+            return invalid("too many intervals requested");
+        }
+
+        Ok(())
+    }
+
+    /// Runs the query for a facility, without validating it
+    pub async fn fetch(&self, db: &Pool<Postgres>, facility: &str) -> Result<History, Error> {
+        struct DbEntry {
+            measured_at: DateTime<Utc>,
+            value: Option<f64>,
+        }
+
+        // each interval is joined with the measurements in the same interval in each of the previous `periods` periods, then the lowest and highest `trim` of each interval's measurements are dropped
+        // the calendar period kind is looked up once per day in range rather than once per joined measurement
+        let history = sqlx::query_as!(
+            DbEntry,
+            r#"
+                WITH
+                    target AS (
+                        SELECT calendar_kind($11::date) as kind
+                    ),
+                    excluded_days AS (
+                        SELECT days.day::date as day
+                        FROM generate_series(
+                            (($1::timestamptz - $8::interval * ($5::int - 1)) AT TIME ZONE 'UTC')::date,
+                            (($2::timestamptz + $3::interval) AT TIME ZONE 'UTC')::date,
+                            '1 day'::interval
+                        ) as days(day)
+                        CROSS JOIN target
+                        WHERE $11::date IS NOT NULL AND calendar_kind(days.day::date) IS DISTINCT FROM target.kind
+                    )
+                SELECT
+                    samples.int_start as "measured_at!",
+                    CASE $4::text
+                        WHEN 'avg' THEN AVG(samples.value)::float8
+                        WHEN 'min' THEN MIN(samples.value)::float8
+                        WHEN 'max' THEN MAX(samples.value)::float8
+                        WHEN 'p50' THEN percentile_cont(0.5) WITHIN GROUP (ORDER BY samples.value) FILTER (WHERE $4::text = 'p50')
+                        WHEN 'p90' THEN percentile_cont(0.9) WITHIN GROUP (ORDER BY samples.value) FILTER (WHERE $4::text = 'p90')
+                        WHEN 'count' THEN COUNT(samples.value)::float8
+                    END as "value"
+                FROM (
+                    SELECT
+                        intervals.int_start,
+                        measurements.value,
+                        row_number() OVER (PARTITION BY intervals.int_start ORDER BY measurements.value) as i,
+                        COUNT(measurements.value) OVER (PARTITION BY intervals.int_start) as n
+                    FROM generate_series($1::timestamptz, $2::timestamptz, $3::interval) as intervals(int_start)
+                    CROSS JOIN generate_series(0, $5::int - 1) as periods(n)
+                    LEFT JOIN measurements ON (
+                        measurements.facility_id = $6 AND
+                        measurements.measured_at >= intervals.int_start - $8::interval * periods.n AND
+                        measurements.measured_at < intervals.int_start - $8::interval * periods.n + $3::interval AND
+                        (NOT $7::bool OR measurements.value > 0) AND
+                        (NOT $10::bool OR NOT measurements.suspect) AND
+                        (measurements.measured_at AT TIME ZONE 'UTC')::date NOT IN (SELECT day FROM excluded_days)
+                    )
+                ) as samples
+                WHERE
+                    samples.value IS NULL OR
+                    (samples.i > floor(samples.n * $9::float8) AND samples.i <= samples.n - floor(samples.n * $9::float8))
+                GROUP BY samples.int_start
+                ORDER BY samples.int_start DESC;
+            "#,
+            self.from,
+            self.to,
+            PgInterval::try_from(self.interval).unwrap(),
+            self.aggregate.as_str(),
+            i32::try_from(self.periods).unwrap_or(i32::MAX),
+            facility,
+            self.exclude_zero,
+            PgInterval::try_from(self.period).unwrap(),
+            self.trim,
+            self.exclude_suspect,
+            self.same_period_as,
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(History {
+            latest: history.first().map_or(self.to, |entry| entry.measured_at),
+            interval: self.interval,
+            aggregate: self.aggregate,
+            closed: vec![false; history.len()],
+            values: history.into_iter().map(|entry| entry.value).collect(),
+        })
+    }
+}
+
+impl History {
+    /// Values rounded to whole percentages
+    pub fn percentages(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = Option<u8>> + ExactSizeIterator + '_ {
+        self.values
+            .iter()
+            .map(|value| value.map(|v| v.round().clamp(0.0, f64::from(CLOSED - 1)) as u8))
+    }
+
+    /// Values in the binary format, with `CLOSED` for intervals outside opening hours and `NO_DATA` for those without measurements
+    pub fn bytes(&self) -> impl DoubleEndedIterator<Item = u8> + ExactSizeIterator + '_ {
+        self.percentages()
+            .zip(&self.closed)
+            .map(|(value, closed)| match (value, closed) {
+                (_, true) => CLOSED,
+                (Some(value), false) => value,
+                (None, false) => NO_DATA,
+            })
+    }
+
+    /// Values with the timestamps of their intervals, most recent first
+    pub fn entries(&self) -> impl Iterator<Item = HistoryEntry> + '_ {
+        let interval = TimeDelta::from_std(self.interval).unwrap();
+
+        self.values
+            .iter()
+            .zip(&self.closed)
+            .enumerate()
+            .map(move |(i, (value, &closed))| HistoryEntry {
+                timestamp: (self.latest - interval * i as i32).timestamp(),
+                value: value.filter(|_| !closed),
+                closed,
+            })
+    }
+
+    /// Encodes the history in `format`, either as in the `IntoResponse` impl or a JSON array of `HistoryEntry`
+    pub fn into_response_as(self, format: Format) -> Response {
+        match format {
+            Format::Binary => (VARY_ACCEPT, self).into_response(),
+            Format::Json => (
+                VARY_ACCEPT,
+                TypedHeader(
+                    CacheControl::new()
+                        .with_max_age(HISTORY_MAX_AGE)
+                        .with_public(),
+                ),
+                Json(self.entries().collect::<Vec<_>>()),
+            )
+                .into_response(),
+        }
+    }
+
+    /// Marks the intervals entirely outside `hours` as closed
+    pub fn mark_closed(&mut self, hours: &OpeningHours) {
+        let interval = TimeDelta::from_std(self.interval).unwrap();
+
+        self.closed = (0..self.values.len())
+            .map(|i| {
+                let start = self.latest - interval * i as i32;
+                hours.is_closed_throughout(start, start + interval)
+            })
+            .collect();
+    }
+}
+
+impl TryFrom<HistoryParams> for HistoryQuery {
+    type Error = Error;
+
+    fn try_from(params: HistoryParams) -> Result<Self, Error> {
+        let timestamp = |t: i64| {
+            DateTime::from_timestamp(t, 0)
+                .ok_or_else(|| Error::InvalidHistoryQuery(format!("invalid timestamp {t}")))
+        };
+
+        let to = params
+            .to
+            .map(timestamp)
+            .transpose()?
+            .unwrap_or_else(Utc::now);
+        let from = params
+            .from
+            .map(timestamp)
+            .transpose()?
+            .unwrap_or(to - TimeDelta::days(1));
+
+        let (periods, period) = match (params.days, params.weeks) {
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidHistoryQuery(
+                    "days and weeks cannot both be specified".to_owned(),
+                ))
+            }
+            (_, Some(weeks)) => (weeks, WEEK),
+            (days, None) => (days.unwrap_or(1), DAY),
+        };
+
+        Ok(Self {
+            from,
+            to,
+            interval: Duration::from_secs(params.interval.unwrap_or(5 * 60)),
+            aggregate: params.aggregate,
+            periods,
+            period,
+            trim: params.trim.unwrap_or(0.0),
+            exclude_zero: false,
+            exclude_suspect: false,
+            same_period_as: None,
+        })
+    }
+}
+
+impl IntoResponse for History {
+    /// Binary encoding of the values, one byte per interval as in `History::bytes`, or a big-endian `u32` per interval for counts
+    fn into_response(self) -> Response {
+        let body = if self.aggregate == Aggregate::Count {
+            self.values
+                .iter()
+                .flat_map(|value| (value.unwrap_or(0.0) as u32).to_be_bytes())
+                .collect::<Vec<u8>>()
+        } else {
+            self.bytes().collect::<Vec<u8>>()
+        };
+
+        (
+            TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
+            TypedHeader(
+                CacheControl::new()
+                    .with_max_age(HISTORY_MAX_AGE)
+                    .with_public(),
+            ),
+            TypedHeader(HistoryLatest(self.latest)),
+            TypedHeader(HistoryInterval(self.interval)),
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Gets the history of a facility over an arbitrary window, interval and aggregate
+pub async fn history(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+    Query(params): Query<HistoryParams>,
+) -> Result<Response, Error> {
+    let query = HistoryQuery::try_from(params)?;
+    query.validate()?;
+
+    let mut history = query.fetch(&db, &facility).await?;
+    // values combined over several periods are typical of the regular hours rather than those of a specific date
+    if query.periods == 1 {
+        history.mark_closed(&hours);
+    } else {
+        history.mark_closed(&hours.regular());
+    }
+
+    Ok(history.into_response_as(format))
+}
+
+struct HistoryLatest(DateTime<Utc>);
+
+impl Header for HistoryLatest {
+    fn name() -> &'static HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("history-latest");
+        &NAME
+    }
+
+    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        Err(headers::Error::invalid())
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let value = HeaderValue::from_str(&self.0.timestamp().to_string()).unwrap();
+        values.extend(once(value));
+    }
+}
+
+pub(super) struct HistoryInterval(pub Duration);
+
+impl Header for HistoryInterval {
+    fn name() -> &'static HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("history-interval");
+        &NAME
+    }
+
+    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        Err(headers::Error::invalid())
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let value = HeaderValue::from_str(&self.0.as_secs().to_string()).unwrap();
+        values.extend(std::iter::once(value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> HistoryParams {
+        HistoryParams {
+            from: Some(1_700_000_000),
+            to: Some(1_700_086_400),
+            interval: None,
+            aggregate: Aggregate::Avg,
+            days: None,
+            weeks: None,
+            trim: None,
+        }
+    }
+
+    fn query(params: HistoryParams) -> Result<HistoryQuery, Error> {
+        HistoryQuery::try_from(params)
+    }
+
+    fn is_invalid(result: Result<(), Error>) -> bool {
+        matches!(result, Err(Error::InvalidHistoryQuery(_)))
+    }
+
+    #[test]
+    fn defaults_to_a_day_of_five_minute_intervals() {
+        let query = query(HistoryParams {
+            from: None,
+            ..params()
+        })
+        .unwrap();
+
+        assert_eq!(query.to - query.from, TimeDelta::days(1));
+        assert_eq!(query.interval, Duration::from_secs(5 * 60));
+        assert_eq!((query.periods, query.period), (1, DAY));
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn weeks_repeat_weekly() {
+        let query = query(HistoryParams {
+            weeks: Some(4),
+            ..params()
+        })
+        .unwrap();
+
+        assert_eq!((query.periods, query.period), (4, WEEK));
+    }
+
+    #[test]
+    fn rejects_days_and_weeks() {
+        let result = query(HistoryParams {
+            days: Some(7),
+            weeks: Some(1),
+            ..params()
+        });
+
+        assert!(matches!(result, Err(Error::InvalidHistoryQuery(_))));
+    }
+
+    #[test]
+    fn rejects_interval_out_of_range() {
+        for interval in [59, 366 * 24 * 60 * 60 + 1] {
+            let query = query(HistoryParams {
+                interval: Some(interval),
+                ..params()
+            })
+            .unwrap();
+
+            assert!(is_invalid(query.validate()), "accepted {interval}");
+        }
+    }
+
+    #[test]
+    fn rejects_to_before_from() {
+        let query = query(HistoryParams {
+            from: Some(1_700_086_400),
+            to: Some(1_700_000_000),
+            ..params()
+        })
+        .unwrap();
+
+        assert!(is_invalid(query.validate()));
+    }
+
+    #[test]
+    fn rejects_zero_periods() {
+        let query = query(HistoryParams {
+            days: Some(0),
+            ..params()
+        })
+        .unwrap();
+
+        assert!(is_invalid(query.validate()));
+    }
+
+    #[test]
+    fn rejects_trim_out_of_range() {
+        for trim in [-0.1, 0.5, f64::NAN] {
+            let query = query(HistoryParams {
+                trim: Some(trim),
+                ..params()
+            })
+            .unwrap();
+
+            assert!(is_invalid(query.validate()), "accepted {trim}");
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_buckets() {
+        // 288 intervals a day over 35 days
+        let query = query(HistoryParams {
+            days: Some(35),
+            ..params()
+        })
+        .unwrap();
+        assert!(is_invalid(query.validate()));
+
+        let query = HistoryQuery {
+            periods: 34,
+            ..query
+        };
+        assert!(query.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_timestamps_out_of_range() {
+        for (from, to) in [
+            (-86_400, 0),
+            (MAX_TIMESTAMP, MAX_TIMESTAMP + 1),
+            (0, 8_000_000_000_000),
+        ] {
+            let query = query(HistoryParams {
+                from: Some(from),
+                to: Some(to),
+                interval: Some(366 * 24 * 60 * 60),
+                ..params()
+            })
+            .unwrap();
+
+            assert!(is_invalid(query.validate()), "accepted {from} to {to}");
+        }
+    }
+
+    #[test]
+    fn rejects_timestamps_out_of_chrono_range() {
+        let result = query(HistoryParams {
+            to: Some(i64::MAX),
+            ..params()
+        });
+
+        assert!(matches!(result, Err(Error::InvalidHistoryQuery(_))));
+    }
+}
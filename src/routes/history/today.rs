@@ -1,130 +1,49 @@
 //! Gets the busyness history for the current day
 
 use {
-    crate::{AppState, HISTORY_MAX_AGE},
-    axum::{
-        extract::State,
-
-        http::{HeaderName, HeaderValue},
-        response::IntoResponse,
-
-    },
-    axum_extra::{
-        headers::{self, CacheControl, ContentType, Header},
-        TypedHeader,
+    crate::{
+        error::Error,
+        hours::OpeningHours,
+        routes::{
+            history::query::{Aggregate, HistoryQuery, DAY},
+            Facility, FacilityHours, Format,
+        },
+        AppState,
     },
-    chrono::{DateTime, Utc},
-    mime_guess::mime::APPLICATION_OCTET_STREAM,
-    sqlx::postgres::types::PgInterval,
-    std::{iter::once, time::Duration},
+    axum::{extract::State, response::Response},
+    chrono::{TimeDelta, Utc},
+    std::time::Duration,
 };
 
 /// Size of time intervals in which to group and average measurements in
 const INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Entry {
-    pub value: u8,
-    pub timestamp: i64,
-}
-
-pub async fn today(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
-    struct DbEntry {
-        measured_at: DateTime<Utc>,
-        value: i16,
-    }
-
-    // get entries today from 6:00 to 22:00
-    let history = sqlx::query_as!(
-        DbEntry,
-        r#"
-            SELECT
-                intervals.int_start as "measured_at!",
-                CASE
-                    WHEN COUNT(measurements.value) > 0 THEN AVG(measurements.value)::smallint
-                    ELSE 255::smallint
-                END as "value!"
-            FROM (
-                SELECT
-                    generate_series(
-                        date_trunc('day', NOW()) + interval '6 hours',
-                        date_trunc('day', NOW()) + interval '22 hours',
-                        $1::interval
-                    ) as int_start
-            ) as intervals
-            LEFT JOIN measurements ON (
-                measurements.measured_at >= intervals.int_start AND
-                measurements.measured_at < intervals.int_start + $1::interval
-            )
-            GROUP BY intervals.int_start
-            ORDER BY intervals.int_start DESC;
-        "#,
-        PgInterval::try_from(INTERVAL).unwrap()
-    )
-    .fetch_all(&db)
-    .await
-    .unwrap();
-
-    let latest_timestamp = history.first().unwrap().measured_at;
-
-    let body = history
-        .into_iter()
-        .map(|DbEntry { value, .. }| value.try_into().unwrap())
-        .collect::<Vec<u8>>();
-
-    (
-        TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(HISTORY_MAX_AGE)
-                .with_public(),
-        ),
-        TypedHeader(HistoryLatest(latest_timestamp)),
-        TypedHeader(HistoryInterval(INTERVAL)),
-        body,
-    )
+pub async fn today(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+) -> Result<Response, Error> {
+    let mut history = query(&hours).fetch(&db, &facility).await?;
+    history.mark_closed(&hours);
+    Ok(history.into_response_as(format))
 }
 
-struct HistoryLatest(DateTime<Utc>);
-
-impl Header for HistoryLatest {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-latest");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.timestamp().to_string()).unwrap();
-        values.extend(once(value));
-    }
-}
-
-struct HistoryInterval(Duration);
-
-impl Header for HistoryInterval {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-interval");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.as_secs().to_string()).unwrap();
-        values.extend(std::iter::once(value));
+/// Gets entries during today's opening hours, or the span of the regular hours if closed today
+pub fn query(hours: &OpeningHours) -> HistoryQuery {
+    let today = hours.date(Utc::now());
+    let (opens, closes) = hours.window(today, hours.on(today));
+
+    HistoryQuery {
+        from: opens,
+        to: opens.max(closes - TimeDelta::from_std(INTERVAL).unwrap()),
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        periods: 1,
+        period: DAY,
+        trim: 0.0,
+        exclude_zero: false,
+        exclude_suspect: false,
+        same_period_as: None,
     }
 }
@@ -1,130 +1,104 @@
-/// Gets the average busyness for each day of the past year
+//! Gets the average busyness for each day of the past year
+
 use {
-    crate::{AppState, HISTORY_MAX_AGE},
-    axum::{
-        extract::State,
-        http::{HeaderName, HeaderValue},
-        response::IntoResponse,
+    crate::{
+        error::Error,
+        routes::{
+            history::query::{Aggregate, History},
+            Facility, FacilityHours, Format,
+        },
+        AppState,
     },
-    axum_extra::{
-        headers::{self, CacheControl, ContentType, Header},
-        TypedHeader,
+    axum::{
+        extract::{Query, State},
+        response::Response,
     },
-    chrono::{DateTime, Utc},
-    mime_guess::mime::APPLICATION_OCTET_STREAM,
-    sqlx::postgres::types::PgInterval,
-    std::{iter::once, time::Duration},
+    chrono::{NaiveTime, TimeDelta, Utc},
+    serde::Deserialize,
+    std::time::Duration,
 };
 
-/// Window in which to retrieve measurements from
-const QUERY_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 2);
+/// Number of days before today to include
+const DAYS: i64 = 365;
 
 /// Size of time intervals in which to group and average measurements in
 const INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Entry {
-    pub value: u8,
-    pub timestamp: i64,
+/// Daily statistic to return
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DailyValue {
+    #[default]
+    Avg,
+    Min,
+    Max,
+    /// UTC hour of the day with the highest average
+    PeakHour,
 }
 
-pub async fn year(State(AppState { db, .. }): State<AppState>) -> impl IntoResponse {
+#[derive(Debug, Deserialize)]
+pub struct YearParams {
+    #[serde(default)]
+    pub value: DailyValue,
+}
+
+/// Gets a daily statistic for today and each of the past 365 days, most recent first, from the daily rollups, with days the facility was closed marked as such
+pub async fn year(
+    State(AppState { db, .. }): State<AppState>,
+    Facility(facility): Facility,
+    FacilityHours(hours): FacilityHours,
+    format: Format,
+    Query(YearParams { value }): Query<YearParams>,
+) -> Result<Response, Error> {
     struct DbEntry {
-        measured_at: DateTime<Utc>,
-        value: i16,
+        avg: Option<f32>,
+        min: Option<i16>,
+        max: Option<i16>,
+        peak_hour: Option<i16>,
     }
 
-    let history = sqlx::query_as!(
+    let today = Utc::now().date_naive();
+
+    let days = sqlx::query_as!(
         DbEntry,
         r#"
             SELECT
-                intervals.int_start as "measured_at!",
-                CASE
-                    WHEN COUNT(measurements.value) > 0 THEN AVG(measurements.value)::smallint
-                    ELSE 255::smallint
-                END as "value!"
-            FROM (
-                SELECT
-                    generate_series(
-                        date_trunc('minute', NOW() - $1::interval),
-                        NOW(),
-                        $2::interval
-                    ) as int_start
-            ) as intervals
-            LEFT JOIN measurements ON (
-                measurements.measured_at >= intervals.int_start AND
-                measurements.measured_at < intervals.int_start + $2::interval
+                daily_rollups.avg as "avg?",
+                daily_rollups.min as "min?",
+                daily_rollups.max as "max?",
+                daily_rollups.peak_hour as "peak_hour?"
+            FROM generate_series($1::date, $2::date, interval '1 day') as days(day)
+            LEFT JOIN daily_rollups ON (
+                daily_rollups.facility_id = $3 AND
+                daily_rollups.day = days.day::date
             )
-            GROUP BY intervals.int_start
-            ORDER BY intervals.int_start DESC;
+            ORDER BY days.day DESC;
         "#,
-        PgInterval::try_from(QUERY_WINDOW).unwrap(),
-        PgInterval::try_from(INTERVAL).unwrap()
+        today - TimeDelta::days(DAYS),
+        today,
+        facility,
     )
     .fetch_all(&db)
-    .await
-    .unwrap();
-
-    let latest_timestamp = history.first().unwrap().measured_at;
-
-    let body = history
-        .into_iter()
-        .map(|DbEntry { value, .. }| value.try_into().unwrap())
-        .collect::<Vec<u8>>();
-
-    (
-        TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(HISTORY_MAX_AGE)
-                .with_public(),
-        ),
-        TypedHeader(HistoryLatest(latest_timestamp)),
-        TypedHeader(HistoryInterval(INTERVAL)),
-        body,
-    )
-}
-
-struct HistoryLatest(DateTime<Utc>);
-
-impl Header for HistoryLatest {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-latest");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.timestamp().to_string()).unwrap();
-        values.extend(once(value));
-    }
-}
-
-struct HistoryInterval(Duration);
-
-impl Header for HistoryInterval {
-    fn name() -> &'static HeaderName {
-        static NAME: HeaderName = HeaderName::from_static("history-interval");
-        &NAME
-    }
-
-    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i HeaderValue>,
-    {
-        Err(headers::Error::invalid())
-    }
-
-    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
-        let value = HeaderValue::from_str(&self.0.as_secs().to_string()).unwrap();
-        values.extend(std::iter::once(value));
-    }
+    .await?;
+
+    let values = days
+        .iter()
+        .map(|day| match value {
+            DailyValue::Avg => day.avg.map(f64::from),
+            DailyValue::Min => day.min.map(f64::from),
+            DailyValue::Max => day.max.map(f64::from),
+            DailyValue::PeakHour => day.peak_hour.map(f64::from),
+        })
+        .collect();
+
+    let mut history = History {
+        latest: today.and_time(NaiveTime::MIN).and_utc(),
+        interval: INTERVAL,
+        aggregate: Aggregate::Avg,
+        values,
+        closed: vec![false; days.len()],
+    };
+    history.mark_closed(&hours);
+
+    Ok(history.into_response_as(format))
 }
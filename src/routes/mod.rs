@@ -1,11 +1,29 @@
 use axum::{http::Uri, response::IntoResponse};
 
+mod best_time;
+mod export;
+mod facilities;
+mod forecast;
+mod format;
 mod health;
 pub mod history;
 mod static_files;
 mod status;
+mod stream;
+mod ws;
 
-pub use {health::health, static_files::static_files, status::status};
+pub use {
+    best_time::best_time,
+    export::export_measurements,
+    facilities::{facilities, Facility, FacilityHours},
+    forecast::forecast,
+    format::{Format, VARY_ACCEPT},
+    health::health,
+    static_files::static_files,
+    status::status,
+    stream::status_stream,
+    ws::ws,
+};
 
 pub async fn index() -> impl IntoResponse {
     static_files(Uri::from_static("/index.html")).await
@@ -1,29 +1,118 @@
 use {
-    crate::{AppState, STATUS_MAX_AGE_DIVISOR},
-    axum::{extract::State, response::IntoResponse},
+    crate::{
+        routes::{Facility, Format, VARY_ACCEPT},
+        AppState, STATUS_MAX_AGE_DIVISOR,
+    },
+    axum::{
+        extract::State,
+        http::{HeaderName, HeaderValue},
+        response::{IntoResponse, Response},
+        Json,
+    },
     axum_extra::{
-        headers::{CacheControl, ContentType},
+        headers::{self, CacheControl, ContentType, Header},
         TypedHeader,
     },
     mime_guess::mime::APPLICATION_OCTET_STREAM,
-    std::{sync::atomic::Ordering::Relaxed, time::Duration},
+    serde::Serialize,
+    std::{iter::once, time::Duration},
 };
 
-/// Gets current gym occupancy
+/// Current occupancy in the JSON format
+#[derive(Debug, Serialize)]
+pub struct StatusEntry {
+    /// UNIX timestamp of the reading, or null if no reading has been made yet
+    pub timestamp: Option<i64>,
+    /// Occupancy percentage, or null if no reading has been made yet
+    pub value: Option<u8>,
+    /// Whether the reading is missing or too old to be trusted
+    pub stale: bool,
+}
+
+/// Gets current facility occupancy, negotiated as in `Format`
+///
+/// The binary body is a single byte, which is 0 if no reading has been made yet. The `Status-Measured-At` header contains the UNIX timestamp of the reading, and `Status-Stale` whether it is missing or too old to be trusted.
 pub async fn status(
     State(AppState {
-        capacity, config, ..
+        statuses, config, ..
     }): State<AppState>,
-) -> impl IntoResponse {
-    (
-        TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(Duration::from_secs(
-                    config.fetch_interval / STATUS_MAX_AGE_DIVISOR,
-                ))
-                .with_public(),
-        ),
-        [capacity.load(Relaxed)],
-    )
+    Facility(facility): Facility,
+    format: Format,
+) -> Response {
+    let snapshot = statuses[&facility].snapshot();
+
+    let cache_control = TypedHeader(
+        CacheControl::new()
+            .with_max_age(Duration::from_secs(
+                config.fetch_interval / STATUS_MAX_AGE_DIVISOR,
+            ))
+            .with_public(),
+    );
+
+    match format {
+        Format::Json => (
+            VARY_ACCEPT,
+            cache_control,
+            Json(StatusEntry {
+                timestamp: snapshot.measured_at,
+                value: snapshot.value,
+                stale: snapshot.stale,
+            }),
+        )
+            .into_response(),
+        Format::Binary => (
+            VARY_ACCEPT,
+            TypedHeader(ContentType::from(APPLICATION_OCTET_STREAM)),
+            cache_control,
+            snapshot
+                .measured_at
+                .map(|t| TypedHeader(StatusMeasuredAt(t))),
+            TypedHeader(StatusStale(snapshot.stale)),
+            [snapshot.value.unwrap_or(0)],
+        )
+            .into_response(),
+    }
+}
+
+struct StatusMeasuredAt(i64);
+
+impl Header for StatusMeasuredAt {
+    fn name() -> &'static HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("status-measured-at");
+        &NAME
+    }
+
+    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        Err(headers::Error::invalid())
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        values.extend(once(HeaderValue::from(self.0)));
+    }
+}
+
+struct StatusStale(bool);
+
+impl Header for StatusStale {
+    fn name() -> &'static HeaderName {
+        static NAME: HeaderName = HeaderName::from_static("status-stale");
+        &NAME
+    }
+
+    fn decode<'i, I>(_: &mut I) -> Result<Self, headers::Error>
+    where
+        Self: Sized,
+        I: Iterator<Item = &'i HeaderValue>,
+    {
+        Err(headers::Error::invalid())
+    }
+
+    fn encode<E: Extend<HeaderValue>>(&self, values: &mut E) {
+        let value = HeaderValue::from_static(if self.0 { "true" } else { "false" });
+        values.extend(once(value));
+    }
 }
@@ -0,0 +1,40 @@
+use {
+    crate::{routes::Facility, status::StatusUpdate, AppState, KEEP_ALIVE_INTERVAL},
+    axum::{
+        extract::State,
+        response::sse::{Event, KeepAlive, Sse},
+    },
+    std::convert::Infallible,
+    tokio_stream::{once, wrappers::BroadcastStream, Stream, StreamExt},
+};
+
+/// Streams live facility occupancy as server-sent `status` events
+///
+/// Each event contains a JSON encoded `StatusUpdate`, starting with the current reading if there is one.
+pub async fn status_stream(
+    State(AppState {
+        statuses, updates, ..
+    }): State<AppState>,
+    Facility(facility): Facility,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    // subscribe before reading the current status so no update is missed in between
+    let receiver = updates.subscribe();
+
+    let current = statuses[&facility].get().map(|reading| StatusUpdate {
+        facility_id: facility.clone(),
+        value: reading.value,
+        measured_at: reading.measured_at.timestamp(),
+    });
+
+    let updates = BroadcastStream::new(receiver)
+        // lagging subscribers skip missed updates
+        .filter_map(Result::ok)
+        .filter(move |update| update.facility_id == facility);
+
+    let stream = once(current)
+        .filter_map(|update| update)
+        .chain(updates)
+        .map(|update| Ok(Event::default().event("status").json_data(update).unwrap()));
+
+    Sse::new(stream).keep_alive(KeepAlive::new().interval(KEEP_ALIVE_INTERVAL))
+}
@@ -0,0 +1,221 @@
+//! WebSocket API for subscribing to live updates of multiple facilities
+//!
+//! All messages are JSON objects with a protocol version `v` and a `type`. Clients send `subscribe` and `unsubscribe` messages listing facility IDs, and receive `status` messages for each new reading of a subscribed facility as well as `history` messages with today's history on subscription and periodically afterwards.
+
+use {
+    crate::{routes::history::today_query, status::StatusUpdate, AppState},
+    axum::{
+        extract::{
+            ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
+            State,
+        },
+        response::Response,
+    },
+    serde::{Deserialize, Serialize},
+    std::{collections::BTreeSet, time::Duration},
+    tokio::{
+        sync::broadcast::error::RecvError,
+        time::{interval, MissedTickBehavior},
+    },
+    tracing::{debug, error},
+};
+
+/// Current protocol version
+const VERSION: u8 = 1;
+
+/// Interval between history messages for subscribed facilities
+const HISTORY_REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Message sent by the client
+#[derive(Debug, Deserialize)]
+pub struct ClientEnvelope {
+    pub v: u8,
+    #[serde(flatten)]
+    pub message: ClientMessage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Subscribe { facilities: Vec<String> },
+    Unsubscribe { facilities: Vec<String> },
+}
+
+/// Message sent by the server
+#[derive(Debug, Serialize)]
+pub struct ServerEnvelope {
+    pub v: u8,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// New reading of a facility
+    Status(StatusUpdate),
+    /// Today's history of a facility
+    History {
+        facility_id: String,
+        /// UNIX timestamp of the first value
+        latest: i64,
+        /// Number of seconds between values
+        interval: u64,
+        /// Average occupancy of each interval, most recent first, or null if there is no data or the facility is closed
+        values: Vec<Option<u8>>,
+        /// Whether each interval is entirely outside opening hours, most recent first
+        closed: Vec<bool>,
+    },
+    /// Client message could not be handled
+    Error { message: String },
+}
+
+/// Upgrades the connection to a WebSocket serving live updates
+pub async fn ws(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
+    ws.on_upgrade(|socket| async move {
+        if let Err(e) = handle_socket(socket, state).await {
+            debug!("WebSocket closed with error: {e}");
+        }
+    })
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState) -> Result<(), axum::Error> {
+    let mut updates = state.updates.subscribe();
+    let mut subscriptions = BTreeSet::new();
+
+    let mut history_interval = interval(HISTORY_REFRESH_INTERVAL);
+    history_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // first tick completes immediately, history is instead sent on subscription
+    history_interval.tick().await;
+
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let Some(message) = message else {
+                    return Ok(());
+                };
+
+                let text = match message? {
+                    Message::Text(text) => text,
+                    Message::Close(_) => return Ok(()),
+                    _ => continue,
+                };
+
+                let added = match parse(&text) {
+                    Ok(ClientMessage::Subscribe { facilities }) => {
+                        let mut added = vec![];
+                        for facility in facilities {
+                            if !state.statuses.contains_key(&facility) {
+                                let message = format!("Unknown facility {facility:?}");
+                                send(&mut socket, ServerMessage::Error { message }).await?;
+                            } else if subscriptions.insert(facility.clone()) {
+                                added.push(facility);
+                            }
+                        }
+                        added
+                    }
+                    Ok(ClientMessage::Unsubscribe { facilities }) => {
+                        for facility in facilities {
+                            subscriptions.remove(&facility);
+                        }
+                        vec![]
+                    }
+                    Err(message) => {
+                        send(&mut socket, ServerMessage::Error { message }).await?;
+                        vec![]
+                    }
+                };
+
+                for facility in added {
+                    if let Some(reading) = state.statuses[&facility].get() {
+                        let update = StatusUpdate {
+                            facility_id: facility.clone(),
+                            value: reading.value,
+                            measured_at: reading.measured_at.timestamp(),
+                        };
+                        send(&mut socket, ServerMessage::Status(update)).await?;
+                    }
+                    send_history(&mut socket, &state, facility).await?;
+                }
+            }
+
+            update = updates.recv() => {
+                match update {
+                    Ok(update) if subscriptions.contains(&update.facility_id) => {
+                        send(&mut socket, ServerMessage::Status(update)).await?;
+                    }
+                    // lagging subscribers skip missed updates
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => return Ok(()),
+                }
+            }
+
+            _ = history_interval.tick() => {
+                for facility in subscriptions.clone() {
+                    send_history(&mut socket, &state, facility).await?;
+                }
+            }
+        }
+    }
+}
+
+fn parse(text: &Utf8Bytes) -> Result<ClientMessage, String> {
+    let envelope = serde_json::from_str::<ClientEnvelope>(text.as_str())
+        .map_err(|e| format!("Invalid message: {e}"))?;
+
+    if envelope.v != VERSION {
+        return Err(format!(
+            "Unsupported protocol version {}, expected {VERSION}",
+            envelope.v
+        ));
+    }
+
+    Ok(envelope.message)
+}
+
+async fn send(socket: &mut WebSocket, message: ServerMessage) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(&ServerEnvelope {
+        v: VERSION,
+        message,
+    })
+    .unwrap();
+    socket.send(Message::Text(text.into())).await
+}
+
+async fn send_history(
+    socket: &mut WebSocket,
+    state: &AppState,
+    facility_id: String,
+) -> Result<(), axum::Error> {
+    let Some(facility) = state.config.facility(&facility_id) else {
+        return Ok(());
+    };
+
+    let mut history = match today_query(&facility.hours)
+        .fetch(&state.db, &facility_id)
+        .await
+    {
+        Ok(history) => history,
+        Err(e) => {
+            error!("Failed to get history for {facility_id}: {e:?}");
+            return Ok(());
+        }
+    };
+    history.mark_closed(&facility.hours);
+
+    send(
+        socket,
+        ServerMessage::History {
+            facility_id,
+            latest: history.latest.timestamp(),
+            interval: history.interval.as_secs(),
+            values: history
+                .percentages()
+                .zip(&history.closed)
+                .map(|(value, closed)| value.filter(|_| !closed))
+                .collect(),
+            closed: history.closed.clone(),
+        },
+    )
+    .await
+}
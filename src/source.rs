@@ -0,0 +1,354 @@
+//! Sources of occupancy readings
+
+use {
+    async_trait::async_trait,
+    regex::{Match, Regex},
+    reqwest::{Client, ClientBuilder, StatusCode},
+    std::{
+        cmp::Reverse,
+        error::Error,
+        fmt,
+        num::ParseIntError,
+        sync::LazyLock,
+        time::{Duration, Instant},
+    },
+};
+
+/// St Andrews sport centre homepage, which includes the current occupancy
+pub const URL: &str = "https://sport.wp.st-andrews.ac.uk/";
+
+/// Pattern matching the occupancy percentage in the homepage
+pub const PATTERN: &str = r"Occupancy: ([0-9]+)%";
+
+const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/132.0.0.0 Safari/537.36";
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of bytes of text included either side of a snippet's match
+const SNIPPET_CONTEXT: usize = 60;
+
+/// Shortest word of a pattern searched for when looking for the nearest candidate to a failed match
+const MIN_CANDIDATE_WORD: usize = 3;
+
+/// Any percentage, the nearest candidate when no word of a pattern is found
+static PERCENTAGE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[0-9]+(\.[0-9]+)?\s*%").unwrap());
+
+/// Something that can be asked for the current occupancy percentage
+#[async_trait]
+pub trait OccupancySource: Send + Sync {
+    /// Fetches a single occupancy reading
+    async fn fetch(&self) -> Result<u8, SourceError>;
+}
+
+/// Scrapes the occupancy from an HTML page using a regex with a single capture group
+#[derive(Debug, Clone)]
+pub struct HtmlSource {
+    client: Client,
+    url: String,
+    regex: Regex,
+}
+
+/// Details of a single fetch, for diagnosing changes in the upstream page
+#[derive(Debug)]
+pub struct FetchReport {
+    pub url: String,
+    /// HTTP status of the response, if one was received
+    pub status: Option<StatusCode>,
+    /// Time taken to receive the whole response, or to fail
+    pub elapsed: Duration,
+    /// Length of the response body in bytes, if the response was successful and its body received
+    pub length: Option<usize>,
+    /// Extracted occupancy, or why it could not be extracted
+    pub result: Result<u8, SourceError>,
+    /// Text around the match of the pattern, or around the nearest candidate if it did not match
+    pub snippet: Option<String>,
+}
+
+impl HtmlSource {
+    /// Creates a new source fetching `url` and extracting the first capture group of `pattern`
+    pub fn new(url: impl Into<String>, pattern: &str) -> Result<Self, regex::Error> {
+        let client = ClientBuilder::new()
+            .timeout(TIMEOUT)
+            .connect_timeout(TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build()
+            .unwrap();
+
+        Ok(Self {
+            client,
+            url: url.into(),
+            regex: Regex::new(pattern)?,
+        })
+    }
+
+    /// Fetches a single reading, recording the details of the response
+    pub async fn diagnose(&self) -> FetchReport {
+        let start = Instant::now();
+        let mut report = FetchReport {
+            url: self.url.clone(),
+            status: None,
+            elapsed: Duration::ZERO,
+            length: None,
+            result: Err(SourceError::MissingCaptures),
+            snippet: None,
+        };
+
+        let (status, text) = self.fetch_text().await;
+        report.status = status;
+        report.elapsed = start.elapsed();
+
+        let text = match text {
+            Ok(text) => text,
+            Err(e) => {
+                report.result = Err(e);
+                return report;
+            }
+        };
+
+        report.length = Some(text.len());
+        report.result = self.extract(&text);
+        report.snippet = match self.regex.find(&text) {
+            Some(m) => Some(snippet(&text, m.start(), m.end())),
+            None => self.nearest(&text),
+        };
+
+        report
+    }
+
+    /// Gets the status of the response, if one was received, and the text of the page, without reading the body of an error response
+    async fn fetch_text(&self) -> (Option<StatusCode>, Result<String, SourceError>) {
+        let response = match self.client.get(&self.url).send().await {
+            Ok(response) => response,
+            Err(e) => return (None, Err(e.into())),
+        };
+
+        let status = response.status();
+        if !status.is_success() {
+            return (Some(status), Err(SourceError::Http(status)));
+        }
+
+        (Some(status), response.text().await.map_err(Into::into))
+    }
+
+    /// Extracts the occupancy from the first capture group of the pattern
+    fn extract(&self, text: &str) -> Result<u8, SourceError> {
+        let captures = self
+            .regex
+            .captures(text)
+            .ok_or_else(|| SourceError::MissingCaptures)?;
+
+        let percentage = captures.get(1).as_ref().map(Match::as_str).ok_or_else(|| {
+            SourceError::MissingCaptureGroup {
+                text: text.to_owned(),
+                i: 1,
+            }
+        })?;
+
+        percentage
+            .parse()
+            .map_err(|e| SourceError::Parse(e, percentage.to_owned()))
+    }
+
+    /// Finds the text most likely to contain the occupancy when the pattern does not match, around the longest word of the pattern or otherwise the first percentage
+    fn nearest(&self, text: &str) -> Option<String> {
+        // ASCII lowercasing keeps byte offsets the same as in `text`
+        let lowercase = text.to_ascii_lowercase();
+
+        let mut words = self
+            .regex
+            .as_str()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() >= MIN_CANDIDATE_WORD)
+            .map(str::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+        words.sort_by_key(|word| Reverse(word.len()));
+
+        words
+            .iter()
+            .find_map(|word| {
+                lowercase
+                    .find(word.as_str())
+                    .map(|start| (start, start + word.len()))
+            })
+            .or_else(|| PERCENTAGE.find(text).map(|m| (m.start(), m.end())))
+            .map(|(start, end)| snippet(text, start, end))
+    }
+}
+
+impl fmt::Display for FetchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "URL:     {}", self.url)?;
+        match self.status {
+            Some(status) => writeln!(f, "Status:  {status}")?,
+            None => writeln!(f, "Status:  no response")?,
+        }
+        writeln!(f, "Time:    {}ms", self.elapsed.as_millis())?;
+        if let Some(length) = self.length {
+            writeln!(f, "Length:  {length} bytes")?;
+        }
+
+        match &self.result {
+            Ok(value) => writeln!(f, "Value:   {value}%")?,
+            // the error's message includes the whole page
+            Err(SourceError::MissingCaptureGroup { i, .. }) => {
+                writeln!(f, "Error:   no capture group found at index {i}")?
+            }
+            Err(e) => {
+                writeln!(f, "Error:   {e}")?;
+
+                // such as the connection or timeout error underlying a failed request
+                let mut source = e.source();
+                while let Some(cause) = source {
+                    writeln!(f, "Cause:   {cause}")?;
+                    source = cause.source();
+                }
+            }
+        }
+
+        match (&self.snippet, &self.result) {
+            (Some(snippet), Ok(_)) => writeln!(f, "Match:   {snippet}"),
+            (Some(snippet), Err(_)) => writeln!(f, "Nearest: {snippet}"),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+/// Gets the text around `start..end` with whitespace collapsed
+fn snippet(text: &str, start: usize, end: usize) -> String {
+    let mut from = start.saturating_sub(SNIPPET_CONTEXT);
+    while !text.is_char_boundary(from) {
+        from -= 1;
+    }
+    let mut to = (end + SNIPPET_CONTEXT).min(text.len());
+    while !text.is_char_boundary(to) {
+        to += 1;
+    }
+
+    text[from..to]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl Default for HtmlSource {
+    fn default() -> Self {
+        Self::new(URL, PATTERN).unwrap()
+    }
+}
+
+#[async_trait]
+impl OccupancySource for HtmlSource {
+    async fn fetch(&self) -> Result<u8, SourceError> {
+        let (_, text) = self.fetch_text().await;
+
+        self.extract(&text?)
+    }
+}
+
+/// Error occurred while fetching a reading from a source
+#[derive(Debug, thiserror::Error, displaydoc::Display)]
+pub enum SourceError {
+    /// Error during GET request
+    Request(#[from] reqwest::Error),
+    /// Received HTTP error code {0}
+    Http(StatusCode),
+    /// Regex did not match response text
+    MissingCaptures,
+    /// No capture group found at index {i} in text {text}
+    MissingCaptureGroup { text: String, i: usize },
+    /// Failed to parse {1:?} as u8: {0:?}
+    Parse(ParseIntError, String),
+}
+
+impl SourceError {
+    /// Whether the error is likely transient, such as a network failure or server error, rather than a change in the upstream page
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Request(_) => true,
+            Self::Http(status) => status.is_server_error(),
+            Self::MissingCaptures | Self::MissingCaptureGroup { .. } | Self::Parse(..) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet_collapses_whitespace() {
+        let text = "<p>\n  Occupancy:\t42%\n</p>";
+        let start = text.find("Occupancy").unwrap();
+
+        assert_eq!(snippet(text, start, start + 14), "<p> Occupancy: 42% </p>");
+    }
+
+    #[test]
+    fn snippet_includes_context_either_side() {
+        let text = format!("{}Occupancy: 42%{}", "a".repeat(100), "b".repeat(100));
+
+        assert_eq!(
+            snippet(&text, 100, 114),
+            format!(
+                "{}Occupancy: 42%{}",
+                "a".repeat(SNIPPET_CONTEXT),
+                "b".repeat(SNIPPET_CONTEXT)
+            )
+        );
+    }
+
+    #[test]
+    fn snippet_widens_to_char_boundaries() {
+        // the context ends fall inside the 3 byte euro signs
+        let text = format!("{}xxOccupancy: 42%yy{}", "€".repeat(30), "€".repeat(30));
+        let start = text.find("Occupancy").unwrap();
+
+        assert_eq!(
+            snippet(&text, start, start + 14),
+            format!("{}xxOccupancy: 42%yy{}", "€".repeat(20), "€".repeat(20))
+        );
+    }
+
+    #[test]
+    fn nearest_finds_pattern_word_ignoring_case() {
+        let source = HtmlSource::default();
+        let text = format!(
+            "{}<b>OCCUPANCY</b> 42 percent{}",
+            "a".repeat(100),
+            "b".repeat(100)
+        );
+
+        let nearest = source.nearest(&text).unwrap();
+        assert!(nearest.contains("<b>OCCUPANCY</b> 42 percent"));
+        assert!(nearest.len() < text.len());
+    }
+
+    #[test]
+    fn nearest_falls_back_to_percentage() {
+        let source = HtmlSource::default();
+        let text = format!("{}Busy: 42.5 %{}", "a".repeat(100), "b".repeat(100));
+
+        assert!(source.nearest(&text).unwrap().contains("Busy: 42.5 %"));
+    }
+
+    #[test]
+    fn nearest_is_none_without_candidates() {
+        assert_eq!(HtmlSource::default().nearest("<p>Closed</p>"), None);
+    }
+
+    #[test]
+    fn extracts_occupancy() {
+        let source = HtmlSource::default();
+
+        assert_eq!(source.extract("<p>Occupancy: 42%</p>").unwrap(), 42);
+        assert!(matches!(
+            source.extract("<p>Occupancy: 420%</p>"),
+            Err(SourceError::Parse(..))
+        ));
+        assert!(matches!(
+            source.extract("<p>Closed</p>"),
+            Err(SourceError::MissingCaptures)
+        ));
+    }
+}
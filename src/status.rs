@@ -1,124 +1,399 @@
 use {
-    regex::{Match, Regex},
-    reqwest::{Client, ClientBuilder, StatusCode},
+    crate::{
+        anomaly::AnomalyDetector,
+        config::{Config, FacilityConfig},
+        hours::OpeningHours,
+        leader::LeaderLock,
+        notify::notify,
+        retry::RetryPolicy,
+        source::{OccupancySource, SourceError},
+    },
+    chrono::{DateTime, Utc},
+    serde::Serialize,
     sqlx::{Pool, Postgres},
     std::{
-        num::ParseIntError,
-        sync::{
-            atomic::{AtomicU8, Ordering::Relaxed},
-            Arc,
-        },
+        sync::{Arc, RwLock},
         time::Duration,
     },
-    tokio::time::interval,
-    tracing::{error, info},
+    tokio::{
+        sync::broadcast,
+        time::{interval, sleep, MissedTickBehavior},
+    },
+    tracing::{error, info, info_span, warn, Instrument},
 };
 
-const URL: &str = "https://sport.wp.st-andrews.ac.uk/";
+/// Single occupancy reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub value: u8,
+    pub measured_at: DateTime<Utc>,
+}
+
+/// Most recent reading of a facility, shared between its fetcher and the routes
+#[derive(Debug)]
+pub struct LiveStatus {
+    facility_id: String,
+    reading: RwLock<Option<Reading>>,
+    max_age: Duration,
+    hours: OpeningHours,
+    updates: broadcast::Sender<StatusUpdate>,
+}
+
+/// New reading of a facility, published to subscribers of live updates
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusUpdate {
+    pub facility_id: String,
+    pub value: u8,
+    /// UNIX timestamp of the reading
+    pub measured_at: i64,
+}
+
+/// Point-in-time view of a `LiveStatus`
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StatusSnapshot {
+    /// Occupancy percentage, if any reading has been made
+    pub value: Option<u8>,
+    /// UNIX timestamp of the reading
+    pub measured_at: Option<i64>,
+    /// Whether the reading is missing or older than the maximum age, not counting time the facility was closed
+    pub stale: bool,
+}
+
+impl LiveStatus {
+    /// Creates an empty status, whose readings become stale after `max_age` of opening hours and are published to `updates`
+    pub fn new(
+        facility_id: String,
+        max_age: Duration,
+        hours: OpeningHours,
+        updates: broadcast::Sender<StatusUpdate>,
+    ) -> Self {
+        Self {
+            facility_id,
+            reading: RwLock::new(None),
+            max_age,
+            hours,
+            updates,
+        }
+    }
+
+    pub fn get(&self) -> Option<Reading> {
+        *self.reading.read().unwrap()
+    }
+
+    /// Replaces the current reading and publishes an update, unless the current reading is not older than `reading`
+    pub fn set(&self, reading: Reading) {
+        let mut current = self.reading.write().unwrap();
+
+        if current.is_some_and(|current| current.measured_at >= reading.measured_at) {
+            return;
+        }
+
+        *current = Some(reading);
+
+        // only fails if there are no subscribers
+        let _ = self.updates.send(StatusUpdate {
+            facility_id: self.facility_id.clone(),
+            value: reading.value,
+            measured_at: reading.measured_at.timestamp(),
+        });
+    }
+
+    /// Whether `reading` is older than the maximum age, counting only the time the facility has been open since
+    ///
+    /// No readings are fetched while the facility is closed, so the last reading before closing stays current until shortly after it reopens.
+    pub fn is_stale(&self, reading: &Reading) -> bool {
+        self.is_stale_at(reading, Utc::now())
+    }
+
+    fn is_stale_at(&self, reading: &Reading, now: DateTime<Utc>) -> bool {
+        self.hours
+            .open_time(reading.measured_at, now)
+            .to_std()
+            .is_ok_and(|age| age > self.max_age)
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        let reading = self.get();
+
+        let stale = reading.as_ref().is_none_or(|r| self.is_stale(r));
+
+        StatusSnapshot {
+            value: reading.map(|r| r.value),
+            measured_at: reading.map(|r| r.measured_at.timestamp()),
+            stale,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct StatusFetcher {
-    capacity: Arc<AtomicU8>,
+    facility_id: String,
+    status: Arc<LiveStatus>,
     db: Pool<Postgres>,
-    client: Client,
-    regex: Regex,
+    source: Arc<dyn OccupancySource>,
+    retry: RetryPolicy,
+    anomalies: AnomalyDetector,
+    hours: OpeningHours,
 }
 
 impl StatusFetcher {
-    pub async fn init(db: Pool<Postgres>, period: Duration) -> Arc<AtomicU8> {
-        let client = ClientBuilder::new()
-            .timeout(Duration::from_secs(5))
-            .connect_timeout(Duration::from_secs(5))
-            .user_agent("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/132.0.0.0 Safari/537.36")
-            .build()
-            .unwrap();
+    /// Starts fetching a facility's status every fetch interval during its opening hours, returning its live status
+    pub async fn init(
+        db: Pool<Postgres>,
+        config: &Config,
+        facility: &FacilityConfig,
+        source: Arc<dyn OccupancySource>,
+        updates: broadcast::Sender<StatusUpdate>,
+    ) -> Arc<LiveStatus> {
+        let facility_id = facility.id.clone();
+        let period = Duration::from_secs(config.fetch_interval);
+        let stale_after = period * config.stale_after_intervals;
+        let status = Arc::new(LiveStatus::new(
+            facility_id.clone(),
+            stale_after,
+            facility.hours.clone(),
+            updates,
+        ));
 
-        let capacity = Arc::new(AtomicU8::new(0));
+        // seed with the most recent stored reading so restarts do not reset the status
+        match latest_reading(&db, &facility_id).await {
+            Ok(Some(reading)) if !status.is_stale(&reading) => {
+                info!(
+                    "Seeded status for {} with stored reading {:?}",
+                    facility_id, reading
+                );
+                status.set(reading);
+            }
+            Ok(_) => info!("No recent stored reading for {}", facility_id),
+            Err(e) => error!("Failed to load stored reading for {}: {e:?}", facility_id),
+        }
 
         let celf = Self {
-            capacity: capacity.clone(),
+            facility_id,
+            status: status.clone(),
             db,
-            client,
-            regex: Regex::new(r"Occupancy: ([0-9]+)%").unwrap(),
+            source,
+            retry: RetryPolicy::from_config(config),
+            anomalies: AnomalyDetector::from_config(config),
+            hours: facility.hours.clone(),
         };
 
         tokio::spawn(fetcher_task_manager(celf, period));
 
-        capacity
+        status
+    }
+
+    /// Updates the live status from the database, for use when another instance is fetching
+    async fn refresh_status(&self) {
+        match latest_reading(&self.db, &self.facility_id).await {
+            Ok(Some(reading)) => self.status.set(reading),
+            Ok(None) => (),
+            Err(e) => error!(
+                "Failed to refresh status for {} from database: {e:?}",
+                self.facility_id
+            ),
+        }
     }
 
     async fn update_status(&mut self) -> Result<(), StatusUpdateError> {
-        info!("Starting status fetch");
+        info!("Starting status fetch for {}", self.facility_id);
 
-        let response = self.client.get(URL).send().await?;
+        let capacity = self.source.fetch().await?;
+        let reading = Reading {
+            value: capacity,
+            measured_at: Utc::now(),
+        };
 
-        if !response.status().is_success() {
-            return Err(StatusUpdateError::Http(response.status()));
-        }
+        self.status.set(reading);
 
-        let text = response.text().await?;
+        info!(
+            "Finished status fetch for {}, got capacity: {}",
+            self.facility_id, capacity
+        );
 
-        let captures = self
-            .regex
-            .captures(&text)
-            .ok_or_else(|| StatusUpdateError::MissingCaptures)?;
+        store_reading(&self.db, &self.facility_id, &reading).await?;
 
-        let percentage = captures.get(1).as_ref().map(Match::as_str).ok_or_else(|| {
-            StatusUpdateError::MissingCaptureGroup {
-                text: text.clone(),
-                i: 1,
-            }
-        })?;
+        // failing to check for anomalies does not fail the update, which would retry and store a second reading
+        if let Err(e) = self
+            .anomalies
+            .check(&self.db, &self.facility_id, &self.hours, &reading)
+            .await
+        {
+            error!("Failed to check reading for anomalies: {e:?}");
+        }
 
-        let capacity = percentage
-            .parse()
-            .map_err(|e| StatusUpdateError::Parse(e, percentage.to_owned()))?;
+        Ok(())
+    }
+}
 
-        self.capacity.store(capacity, Relaxed);
+/// Stores a reading of a facility and notifies listening instances of it
+pub async fn store_reading(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+    reading: &Reading,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
 
-        info!("Finished status fetch, got capacity: {}", capacity);
+    sqlx::query!(
+        "INSERT INTO measurements (facility_id, measured_at, value) VALUES ($1, $2, $3)",
+        facility_id,
+        reading.measured_at,
+        i16::from(reading.value),
+    )
+    .execute(&mut *tx)
+    .await?;
 
-        sqlx::query!(
-            "INSERT INTO measurements (value) VALUES ($1)",
-            i16::from(capacity),
-        )
-        .execute(&self.db)
-        .await?;
+    notify(&mut *tx, facility_id, reading).await?;
 
-        Ok(())
-    }
+    tx.commit().await
+}
+
+/// Gets the most recent stored reading for a facility
+async fn latest_reading(
+    db: &Pool<Postgres>,
+    facility_id: &str,
+) -> Result<Option<Reading>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT measured_at, value FROM measurements WHERE facility_id = $1 ORDER BY measured_at DESC LIMIT 1",
+        facility_id,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(row.map(|row| Reading {
+        value: u8::try_from(row.value).unwrap_or(u8::MAX),
+        measured_at: row.measured_at,
+    }))
 }
 
 async fn fetcher_task_manager(fetcher: StatusFetcher, period: Duration) {
     loop {
         let res = tokio::spawn(fetcher_task(fetcher.clone(), period)).await;
-        error!("fetcher_task joined with result {:?}", res);
+        error!(
+            "fetcher_task for {} joined with result {:?}",
+            fetcher.facility_id, res
+        );
     }
 }
 
 async fn fetcher_task(mut fetcher: StatusFetcher, period: Duration) {
+    // created per task so that the lock is released if the task panics
+    let mut leader = LeaderLock::new(fetcher.db.clone(), "fetcher", &fetcher.facility_id);
+
     let mut interval = interval(period);
+    // retries may overrun the period, in which case the missed fetch is dropped rather than made immediately
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     loop {
         interval.tick().await;
-        if let Err(e) = fetcher.update_status().await {
-            error!("Error while updating status: {e:?}");
+        if leader.poll().await {
+            // nothing is recorded while closed, rather than readings of 0%
+            if fetcher.hours.is_open(Utc::now()) {
+                update_status_with_retry(&mut fetcher).await;
+            }
+        } else {
+            fetcher.refresh_status().await;
         }
     }
 }
 
+async fn update_status_with_retry(fetcher: &mut StatusFetcher) {
+    let policy = fetcher.retry;
+
+    for attempt in 1..=policy.max_attempts {
+        let span = info_span!(
+            "update_status",
+            facility = %fetcher.facility_id,
+            retry = attempt - 1
+        );
+
+        let Err(e) = fetcher.update_status().instrument(span).await else {
+            return;
+        };
+
+        if !e.is_retryable() || attempt == policy.max_attempts {
+            error!("Error while updating status after {attempt} attempt(s): {e:?}");
+            return;
+        }
+
+        let delay = policy.delay(attempt);
+        warn!("Retryable error while updating status, retrying in {delay:?}: {e}");
+        sleep(delay).await;
+    }
+}
+
 /// Error occurred while updating status
 #[derive(Debug, thiserror::Error, displaydoc::Display)]
 pub enum StatusUpdateError {
-    /// Error during GET request
-    Request(#[from] reqwest::Error),
-    /// Received HTTP error code {0}
-    Http(StatusCode),
-    /// Regex did not match response text
-    MissingCaptures,
-    /// No capture group found at index {i} in text {text}
-    MissingCaptureGroup { text: String, i: usize },
-    /// Failed to parse {1:?} as u8: {0:?}
-    Parse(ParseIntError, String),
+    /// Failed to fetch reading from source: {0}
+    Source(#[from] SourceError),
     /// Database error
     Database(#[from] sqlx::Error),
 }
+
+impl StatusUpdateError {
+    /// Whether the error is likely transient and the update worth retrying
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Source(e) => e.is_retryable(),
+            Self::Database(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, chrono::NaiveDate};
+
+    fn at(day: u32, h: u32, m: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_hms_opt(h, m, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    /// Status open 6:00 to 22:00 whose readings become stale after 3 minutes
+    fn status() -> LiveStatus {
+        LiveStatus::new(
+            "gym".to_owned(),
+            Duration::from_secs(3 * 60),
+            OpeningHours::default(),
+            broadcast::channel(1).0,
+        )
+    }
+
+    fn reading(measured_at: DateTime<Utc>) -> Reading {
+        Reading {
+            value: 42,
+            measured_at,
+        }
+    }
+
+    #[test]
+    fn last_reading_before_closing_is_fresh_overnight() {
+        assert!(!status().is_stale_at(&reading(at(12, 21, 59)), at(13, 2, 0)));
+    }
+
+    #[test]
+    fn last_reading_before_closing_becomes_stale_after_opening() {
+        let status = status();
+        let reading = reading(at(12, 21, 59));
+
+        assert!(!status.is_stale_at(&reading, at(13, 6, 1)));
+        assert!(status.is_stale_at(&reading, at(13, 6, 5)));
+    }
+
+    #[test]
+    fn old_reading_is_stale_while_closed() {
+        assert!(status().is_stale_at(&reading(at(10, 12, 0)), at(13, 2, 0)));
+    }
+
+    #[test]
+    fn reading_becomes_stale_while_open() {
+        let status = status();
+        let reading = reading(at(13, 12, 0));
+
+        assert!(!status.is_stale_at(&reading, at(13, 12, 3)));
+        assert!(status.is_stale_at(&reading, at(13, 12, 4)));
+    }
+}
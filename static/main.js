@@ -3,6 +3,11 @@ window.onload = async (_e) => {
   const array = await response.arrayBuffer();
   update_status(new DataView(array).getUint8(0));
 
+  const events = new EventSource("/status/stream");
+  events.addEventListener("status", (e) => {
+    update_status(JSON.parse(e.data).value);
+  });
+
   await display_data("chart-today", "/history/today");
   await display_data("chart-average", "/history/average");
 };
@@ -39,7 +44,8 @@ async function display_data(id, data_path) {
 
     entries.push({
       x: (latest_timestamp - i * interval) * 1000,
-      y: value == 0xff ? null : value,
+      // no data or closed
+      y: value >= 0xfe ? null : value,
     });
   }
 